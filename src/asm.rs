@@ -1,31 +1,44 @@
-use std::io::Result;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
 
-use crate::line::SourceLine;
+use crate::error::{self, Diagnostic};
+use crate::instruction::{Instruction, Value};
+use crate::line::{SourceLine, VmCommand};
 use crate::parser::LineParser;
+use crate::template::{self, TemplateError, TemplateErrorKind};
 
 #[derive(Debug)]
 pub struct Asm {
     pub comment: String,
-    pub lines: Vec<String>,
+    pub instructions: Vec<Instruction>,
 }
 
 impl Asm {
-    pub fn new(source: &SourceLine, lines: Vec<String>) -> Self {
+    pub fn new(source: &SourceLine, instructions: Vec<Instruction>) -> Self {
         Self {
             comment: format!("//{}", source.source),
-            lines,
+            instructions,
         }
     }
 
     pub fn unkown(comment: &str) -> Self {
         Self {
             comment: format!("//{}", comment),
-            lines: vec!["//UNKOWN".to_string()],
+            instructions: vec![Instruction::Comment("UNKOWN".to_string())],
         }
     }
+}
+
+// the comment followed by one instruction per line
+impl Display for Asm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.comment)?;
 
-    pub fn set_line(&mut self, line_num: usize, new_line: String) {
-        self.lines[line_num] = new_line;
+        for instruction in &self.instructions {
+            writeln!(f, "{instruction}")?;
+        }
+        Ok(())
     }
 }
 
@@ -33,281 +46,283 @@ impl Default for Asm {
     fn default() -> Self {
         Asm {
             comment: "//UNKOWN".to_string(),
-            lines: vec!["//UNKOWN".to_string()],
+            instructions: vec![Instruction::Comment("UNKOWN".to_string())],
         }
     }
 }
 pub struct AsmGen {
-    // pub asm_dir: PathBuf,
     pub lbl_idx: i32,
     pub filename: String,
-    pub asm_dir: String,
+    // function currently being translated, set by function commands
+    pub current_fn: Option<String>,
+    // return labels are numbered per calling function
+    ret_idx: HashMap<String, i32>,
     asm_reader: AsmReader,
     // pub fn_lbl_stack: Vec<String>,
 }
 
 impl AsmGen {
-    pub fn new(filename: &str) -> Result<Self> {
-        // let cwd = env::current_dir().unwrap();
-        // println!("{:?}", cwd);
-        // Ok(Self { asm_dir: cwd })
-        Ok(Self {
+    pub fn new(filename: &str, asm_reader: AsmReader) -> Self {
+        Self {
             filename: filename.to_string(),
-            asm_dir: "src/asm".to_string(),
             lbl_idx: 0,
-            asm_reader: AsmReader::new(),
-        })
+            current_fn: None,
+            ret_idx: HashMap::new(),
+            asm_reader,
+        }
     }
 
-    pub fn gen_init_asm(&mut self) -> Asm {
-        let call_lines = self.asm_reader.call();
-        let new_lbl_idx = &self.next_lbl_idx();
-
-        let ret_addr = format!("{}$ret.{}", self.filename, new_lbl_idx);
-
-        let mut asm = Asm {
-            comment: "// Sys Init bootstrap".to_string(),
-            lines: call_lines,
-        };
-
-        // set asm lines
-        // write return address to stack
-        asm.set_line(0, format!("@{ret_addr}"));
-
-        // set arg offset
-        asm.set_line(35, format!("@{}", "0"));
-        // set function call address
-        asm.set_line(47, format!("@{}", "Sys.init"));
-        // write return label
-        asm.set_line(49, format!("({ret_addr})"));
+    // start translating another file of the same program, label indexes keep
+    // counting so comparison and return labels stay unique across files
+    pub fn set_filename(&mut self, filename: &str) {
+        self.filename = filename.to_string();
+        self.current_fn = None;
+    }
+
+    // set SP and call the entry function, eg. Sys.init
+    pub fn gen_init_asm(&mut self, entry: &str, stack_base: u16) -> Result<Asm, TemplateError> {
+        let ret_addr = self.next_ret_addr();
+
+        let call_lines = self.fill(
+            "call.asm",
+            &self.asm_reader.call,
+            &[
+                // write return address to stack
+                ("RET_ADD", &format!("@{ret_addr}")),
+                // set arg offset
+                ("ARG_OFFSET", "@0"),
+                // set function call address
+                ("FUCNTION_NAME", &format!("@{entry}")),
+                // write return label
+                ("SET_LABEL", &format!("({ret_addr})")),
+            ],
+        )?;
 
         // prepend init asm
-        let init_lines = self.asm_reader.init();
-
-        asm.lines = [init_lines, asm.lines].concat();
-
-        asm
+        let init_lines = self.fill(
+            "init.asm",
+            &self.asm_reader.init,
+            &[("STACK_BASE", &stack_base.to_string())],
+        )?;
+
+        Ok(Asm {
+            comment: format!("// {entry} bootstrap"),
+            instructions: [init_lines, call_lines].concat(),
+        })
     }
 
     // ---
     // Public Asm factory methods
     // ---
 
-    pub fn gen_ret_asm(&mut self, source: &SourceLine) -> Asm {
-        let raw_lines = self.asm_reader.ret();
-
-        Asm {
-            comment: format!("//{}", source.source),
-            lines: raw_lines,
+    pub fn gen_ret_asm(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        let raw_lines = self
+            .fill("return.asm", &self.asm_reader.ret, &[])
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, raw_lines))
+    }
+
+    pub fn gen_func_asm(
+        &mut self,
+        source: &SourceLine,
+        name: &str,
+        n_locals: u16,
+    ) -> Result<Asm, Diagnostic> {
+        // labels that follow are scoped to this function
+        self.current_fn = Some(name.to_string());
+
+        let mut raw_lines = self
+            .fill(
+                "func.asm",
+                &self.asm_reader.func,
+                &[("FUNC_LABEL", &format!("({name})"))],
+            )
+            .map_err(invalid(source))?;
+
+        for n_local_args in 0..n_locals {
+            // get set local lcl to zero asm, at the offset of LCL address
+            let set_lcl_asm = self
+                .fill(
+                    "push_lcl.asm",
+                    &self.asm_reader.push_lcl,
+                    &[("LCL_OFFSET", &format!("@{n_local_args}"))],
+                )
+                .map_err(invalid(source))?;
+
+            raw_lines.extend(set_lcl_asm);
         }
-    }
-
-    pub fn gen_func_asm(&mut self, source: &SourceLine) -> Asm {
-        let raw_lines = self.asm_reader.func();
 
-        let mut asm = Asm {
-            comment: format!("//{}", source.source),
-            lines: raw_lines,
-        };
-
-        // set lines
-        asm.set_line(0, format!("({})", source.args.arg1));
-
-        for n_local_args in 0..source.args.arg2.unwrap() {
-            // get set local lcl to zero asm
-            let mut set_lcl_asm = self.asm_reader.push_lcl();
-
-            // set offset of LCL address
-            set_lcl_asm[0] = format!("@{n_local_args}");
-
-            for line in set_lcl_asm {
-                // set first iteration of push local args
-                asm.lines.push(line.to_string());
-            }
-        }
-
-        asm
+        Ok(Asm::new(source, raw_lines))
     }
 
-    pub fn gen_call_asm(&mut self, source: &SourceLine) -> Asm {
-        let raw_lines = self.asm_reader.call();
-        let new_lbl_idx = &self.next_lbl_idx();
+    pub fn gen_call_asm(
+        &mut self,
+        source: &SourceLine,
+        name: &str,
+        n_args: u16,
+    ) -> Result<Asm, Diagnostic> {
+        let ret_addr = self.next_ret_addr();
 
-        let ret_addr = format!("{}$ret.{}", self.filename, new_lbl_idx);
-
-        let mut asm = Asm {
-            comment: format!("//{}", source.source),
-            lines: raw_lines,
-        };
-
-        // set asm lines
-        // write return address to stack
-        asm.set_line(0, format!("@{ret_addr}"));
-        // set function call address
-        asm.set_line(47, format!("@{}", source.args.arg1));
-        // write return label
-        asm.set_line(49, format!("({ret_addr})"));
-
-        // check if zero args passed to call, add space for return value on stack
-        if source.args.arg2.unwrap() == 0 {
-            // arg offset, number of args passed to funtion
-            asm.set_line(35, "@1".to_string());
-            let mut push_const_asm = self.asm_reader.push_const();
-            push_const_asm[0] = "@0".to_string();
-            push_const_asm.append(&mut asm.lines);
-            asm.lines = push_const_asm;
-        } else {
-            // arg offset, number of args passed to funtion
-            asm.set_line(35, format!("@{}", source.args.arg2.unwrap()));
-        };
+        let raw_lines = self
+            .fill(
+                "call.asm",
+                &self.asm_reader.call,
+                &[
+                    // write return address to stack
+                    ("RET_ADD", &format!("@{ret_addr}")),
+                    // arg offset, number of args passed to funtion
+                    ("ARG_OFFSET", &format!("@{n_args}")),
+                    // set function call address
+                    ("FUCNTION_NAME", &format!("@{name}")),
+                    // write return label
+                    ("SET_LABEL", &format!("({ret_addr})")),
+                ],
+            )
+            .map_err(invalid(source))?;
 
-        asm
+        Ok(Asm::new(source, raw_lines))
     }
 
-    pub fn gen_if_asm(&mut self, source: &SourceLine) -> Asm {
-        let lines = self.asm_reader.if_goto();
+    pub fn gen_if_asm(&mut self, source: &SourceLine, label: &str) -> Result<Asm, Diagnostic> {
+        let label = self.scoped_label(label);
 
-        let label = &source.args.arg1;
+        let lines = self
+            .fill(
+                "if_goto.asm",
+                &self.asm_reader.if_goto,
+                &[("LABEL", &format!("@{label}"))],
+            )
+            .map_err(invalid(source))?;
 
-        let mut asm = Asm {
-            comment: format!("//{}", source.source),
-            lines,
-        };
-        asm.set_line(4, format!("@{label}"));
-        asm
+        Ok(Asm::new(source, lines))
     }
 
-    pub fn gen_goto_asm(&mut self, source: &SourceLine) -> Asm {
-        let lines = vec![format!("@{}", source.args.arg1), "0;JMP".to_string()];
+    pub fn gen_goto_asm(&mut self, source: &SourceLine, label: &str) -> Result<Asm, Diagnostic> {
+        let label = self.scoped_label(label);
+        let symbol = Value::symbol(&label).map_err(|err| source.diagnostic(1, &err.expected))?;
 
-        Asm {
-            comment: format!("//{}", source.source),
-            lines,
-        }
+        let lines = vec![
+            Instruction::A(symbol),
+            Instruction::parse("0;JMP").expect("valid jump"),
+        ];
+
+        Ok(Asm::new(source, lines))
     }
 
-    pub fn gen_label_asm(&mut self, source: &SourceLine) -> Asm {
-        let lines = vec![format!("({})", source.args.arg1)];
+    pub fn gen_label_asm(&mut self, source: &SourceLine, label: &str) -> Result<Asm, Diagnostic> {
+        let label = self.scoped_label(label);
+        let label =
+            Instruction::label(&label).map_err(|err| source.diagnostic(1, &err.expected))?;
 
-        Asm {
-            comment: format!("//{}", source.source),
-            lines,
-        }
+        Ok(Asm::new(source, vec![label]))
     }
 
     // Arithmetic OP
 
-    pub fn gen_add(&mut self, source: &SourceLine) -> Asm {
-        self.gen_sum_asm(&source.source, "M=D+M")
+    pub fn gen_add(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_sum_asm(source, "M=D+M")
     }
 
-    pub fn gen_sub(&mut self, source: &SourceLine) -> Asm {
-        self.gen_sum_asm(&source.source, "M=M-D")
+    pub fn gen_sub(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_sum_asm(source, "M=M-D")
     }
 
-    pub fn gen_and(&mut self, source: &SourceLine) -> Asm {
-        self.gen_sum_asm(&source.source, "M=D&M")
+    pub fn gen_and(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_sum_asm(source, "M=D&M")
     }
 
-    pub fn gen_or(&mut self, source: &SourceLine) -> Asm {
-        self.gen_sum_asm(&source.source, "M=D|M")
+    pub fn gen_or(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_sum_asm(source, "M=D|M")
     }
 
     // cmp template
-    pub fn gen_eq(&mut self, source: &SourceLine) -> Asm {
-        self.gen_cmp_asm(&source.source, "D;JEQ")
+    pub fn gen_eq(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_cmp_asm(source, "D;JEQ")
     }
 
-    pub fn gen_lt(&mut self, source: &SourceLine) -> Asm {
-        self.gen_cmp_asm(&source.source, "D;JLT")
+    pub fn gen_lt(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_cmp_asm(source, "D;JLT")
     }
 
-    pub fn gen_gt(&mut self, source: &SourceLine) -> Asm {
-        self.gen_cmp_asm(&source.source, "D;JGT")
+    pub fn gen_gt(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_cmp_asm(source, "D;JGT")
     }
 
     // negate template
-    pub fn gen_neg(&mut self, source: &SourceLine) -> Asm {
-        self.gen_neg_asm(&source.source, "M=-D")
+    pub fn gen_neg(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_neg_asm(source, "M=-D")
     }
 
-    pub fn gen_not(&mut self, source: &SourceLine) -> Asm {
-        self.gen_neg_asm(&source.source, "M=!D")
+    pub fn gen_not(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        self.gen_neg_asm(source, "M=!D")
     }
 
     // PUSH
 
     // push mem seg template
-    pub fn gen_push(&mut self, source: &SourceLine, mem_seg_lbl: &str) -> Asm {
-        // get index in mem seg to select
-
-        let mem_seg_index = source.args.arg2.unwrap();
-
-        // generate asm
-        self.gen_push_mem_seg_asm(&source.source, mem_seg_index, mem_seg_lbl)
+    pub fn gen_push(
+        &mut self,
+        source: &SourceLine,
+        mem_seg_lbl: &str,
+        index: u16,
+    ) -> Result<Asm, Diagnostic> {
+        self.gen_push_mem_seg_asm(source, index, mem_seg_lbl)
     }
 
     // push static_temp template
-    pub fn gen_push_temp(&mut self, source: &SourceLine) -> Asm {
-        let mem_seg_lbl = format!("R{}", source.args.arg2.unwrap() + 5);
-        self.gen_push_static_temp_asm(&source.source, &mem_seg_lbl)
+    pub fn gen_push_temp(&mut self, source: &SourceLine, index: u16) -> Result<Asm, Diagnostic> {
+        let mem_seg_lbl = format!("R{}", index + 5);
+        self.gen_push_static_temp_asm(source, &mem_seg_lbl)
     }
 
-    pub fn gen_push_static(&mut self, source: &SourceLine) -> Asm {
-        let mem_seg_lbl = format!("{}.{}", self.filename, source.args.arg2.unwrap());
-        self.gen_push_static_temp_asm(&source.source, &mem_seg_lbl)
+    pub fn gen_push_static(&mut self, source: &SourceLine, index: u16) -> Result<Asm, Diagnostic> {
+        let mem_seg_lbl = format!("{}.{}", self.filename, index);
+        self.gen_push_static_temp_asm(source, &mem_seg_lbl)
     }
 
     // const template
-    pub fn gen_push_const(&self, source: &SourceLine, value: i32) -> Asm {
-        let lines = self.asm_reader.push_const();
+    pub fn gen_push_const(&self, source: &SourceLine, value: u16) -> Result<Asm, Diagnostic> {
+        let lines = self
+            .fill(
+                "push_const.asm",
+                &self.asm_reader.push_const,
+                &[("CONST_VAL", &value.to_string())],
+            )
+            .map_err(invalid(source))?;
 
-        let mut asm = Asm {
-            comment: format!("//{}", source.source),
-            lines,
-        };
-        asm.set_line(0, format!("@{value}"));
-        asm
+        Ok(Asm::new(source, lines))
     }
 
-    pub fn gen_push_ptr(&self, source: &SourceLine) -> Asm {
-        match source.args.arg2 {
-            Some(0) => self.gen_push_ptr_asm(&source.source, "THIS"),
-            Some(1) => self.gen_push_ptr_asm(&source.source, "THAT"),
-            _ => Asm::unkown(&source.source),
-        }
+    // mem_seg_lbl is THIS for pointer 0 and THAT for pointer 1
+    pub fn gen_push_ptr(&self, source: &SourceLine, mem_seg_lbl: &str) -> Result<Asm, Diagnostic> {
+        self.gen_push_ptr_asm(source, mem_seg_lbl)
     }
 
     // POP
 
-    pub fn gen_pop(&mut self, source: &SourceLine, mem_seg_lbl: &str) -> Asm {
-        // get index in mem seg to select
-        let mem_seg_index = source.args.arg2.unwrap();
-
-        // generate asm
-        self.gen_pop_mem_seg_asm(&source.source, mem_seg_index, mem_seg_lbl)
+    pub fn gen_pop(
+        &mut self,
+        source: &SourceLine,
+        mem_seg_lbl: &str,
+        index: u16,
+    ) -> Result<Asm, Diagnostic> {
+        self.gen_pop_mem_seg_asm(source, index, mem_seg_lbl)
     }
 
-    pub fn gen_pop_static(&mut self, source: &SourceLine) -> Asm {
-        // get index in mem seg to select
-        let mem_seg_lbl = format!("{}.{}", self.filename, source.args.arg2.unwrap());
-        // generate asm
-        self.gen_pop_static_temp_asm(&source.source, &mem_seg_lbl)
+    pub fn gen_pop_static(&mut self, source: &SourceLine, index: u16) -> Result<Asm, Diagnostic> {
+        let mem_seg_lbl = format!("{}.{}", self.filename, index);
+        self.gen_pop_static_temp_asm(source, &mem_seg_lbl)
     }
 
-    pub fn gen_pop_temp(&mut self, source: &SourceLine) -> Asm {
-        // get index in mem seg to select
-        let mem_seg_lbl = format!("R{}", source.args.arg2.unwrap() + 5);
-        // generate asm
-        self.gen_pop_static_temp_asm(&source.source, &mem_seg_lbl)
+    pub fn gen_pop_temp(&mut self, source: &SourceLine, index: u16) -> Result<Asm, Diagnostic> {
+        let mem_seg_lbl = format!("R{}", index + 5);
+        self.gen_pop_static_temp_asm(source, &mem_seg_lbl)
     }
 
-    pub fn gen_pop_ptr(&self, source: &SourceLine) -> Asm {
-        match source.args.arg2 {
-            Some(0) => self.gen_pop_ptr_asm(&source.source, "THIS"),
-            Some(1) => self.gen_pop_ptr_asm(&source.source, "THAT"),
-            _ => Asm::unkown(&source.source),
-        }
+    // mem_seg_lbl is THIS for pointer 0 and THAT for pointer 1
+    pub fn gen_pop_ptr(&self, source: &SourceLine, mem_seg_lbl: &str) -> Result<Asm, Diagnostic> {
+        self.gen_pop_ptr_asm(source, mem_seg_lbl)
     }
 
     // ---
@@ -317,140 +332,182 @@ impl AsmGen {
     // PUSH
     // ---
 
-    fn gen_push_ptr_asm(&self, comment: &str, mem_seg_lbl: &str) -> Asm {
-        let lines = self.asm_reader.push_ptr();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines,
-        };
-
-        asm.set_line(0, format!("@{mem_seg_lbl}"));
-        asm
-    }
-
-    fn gen_push_static_temp_asm(&mut self, comment: &str, mem_seg_lbl: &str) -> Asm {
-        let lines = self.asm_reader.push_static_temp();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines,
-        };
-
-        asm.set_line(0, format!("@{mem_seg_lbl}"));
-        asm
-    }
-
-    fn gen_push_mem_seg_asm(&mut self, comment: &str, mem_index: i32, mem_seg_lbl: &str) -> Asm {
-        let raw_lines = self.asm_reader.push_mem_seg();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
-
-        asm.set_line(0, format!("@{mem_index}"));
-        asm.set_line(2, format!("@{mem_seg_lbl}"));
-
-        asm
+    fn gen_push_ptr_asm(&self, source: &SourceLine, mem_seg_lbl: &str) -> Result<Asm, Diagnostic> {
+        let lines = self
+            .fill(
+                "push_ptr.asm",
+                &self.asm_reader.push_ptr,
+                &[("MEM_SEG_LBL", &format!("@{mem_seg_lbl}"))],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, lines))
+    }
+
+    fn gen_push_static_temp_asm(
+        &mut self,
+        source: &SourceLine,
+        mem_seg_lbl: &str,
+    ) -> Result<Asm, Diagnostic> {
+        let lines = self
+            .fill(
+                "push_static_temp.asm",
+                &self.asm_reader.push_static_temp,
+                &[("ADDR_LBL", &format!("@{mem_seg_lbl}"))],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, lines))
+    }
+
+    fn gen_push_mem_seg_asm(
+        &mut self,
+        source: &SourceLine,
+        mem_index: u16,
+        mem_seg_lbl: &str,
+    ) -> Result<Asm, Diagnostic> {
+        let raw_lines = self
+            .fill(
+                "push_mem_seg.asm",
+                &self.asm_reader.push_mem_seg,
+                &[
+                    ("OFFSET_INDEX", &format!("@{mem_index}")),
+                    ("MEM_SEG_LABEL", &format!("@{mem_seg_lbl}")),
+                ],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, raw_lines))
     }
 
     // POP
     // ---
 
-    fn gen_pop_ptr_asm(&self, comment: &str, mem_seg_lbl: &str) -> Asm {
-        let lines = self.asm_reader.pop_ptr();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines,
-        };
-
-        asm.set_line(4, format!("@{mem_seg_lbl}"));
-        asm
-    }
-
-    fn gen_pop_mem_seg_asm(&mut self, comment: &str, mem_index: i32, mem_seg_lbl: &str) -> Asm {
-        let raw_lines = self.asm_reader.pop_mem_seg();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
-
-        asm.set_line(6, format!("@{mem_index}"));
-        asm.set_line(8, format!("@{mem_seg_lbl}"));
-
-        asm
-    }
-
-    fn gen_pop_static_temp_asm(&mut self, comment: &str, mem_seg_lbl: &str) -> Asm {
-        let raw_lines = self.asm_reader.pop_static_temp();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
-
-        asm.set_line(4, format!("@{mem_seg_lbl}"));
-        asm
+    fn gen_pop_ptr_asm(&self, source: &SourceLine, mem_seg_lbl: &str) -> Result<Asm, Diagnostic> {
+        let lines = self
+            .fill(
+                "pop_ptr.asm",
+                &self.asm_reader.pop_ptr,
+                &[("MEM_SEG_LBL", &format!("@{mem_seg_lbl}"))],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, lines))
+    }
+
+    fn gen_pop_mem_seg_asm(
+        &mut self,
+        source: &SourceLine,
+        mem_index: u16,
+        mem_seg_lbl: &str,
+    ) -> Result<Asm, Diagnostic> {
+        let raw_lines = self
+            .fill(
+                "pop_mem_seg.asm",
+                &self.asm_reader.pop_mem_seg,
+                &[
+                    ("MEM_SEG_INDEX", &format!("@{mem_index}")),
+                    ("MEM_SEG_LABEL", &format!("@{mem_seg_lbl}")),
+                ],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, raw_lines))
+    }
+
+    fn gen_pop_static_temp_asm(
+        &mut self,
+        source: &SourceLine,
+        mem_seg_lbl: &str,
+    ) -> Result<Asm, Diagnostic> {
+        let raw_lines = self
+            .fill(
+                "pop_static_temp.asm",
+                &self.asm_reader.pop_static_temp,
+                &[("MEM_SEG_LABEL", &format!("@{mem_seg_lbl}"))],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, raw_lines))
     }
 
     // ARITH
     // ---
 
-    fn gen_neg_asm(&self, comment: &str, neg_cmd: &str) -> Asm {
-        let raw_lines = self.asm_reader.neg();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
+    fn gen_neg_asm(&self, source: &SourceLine, neg_cmd: &str) -> Result<Asm, Diagnostic> {
+        // set neg command
+        let raw_lines = self
+            .fill("neg.asm", &self.asm_reader.neg, &[("NEG_COMMAND", neg_cmd)])
+            .map_err(invalid(source))?;
 
-        // set sum command
-        asm.set_line(4, neg_cmd.to_string());
-        asm
+        Ok(Asm::new(source, raw_lines))
     }
 
-    fn gen_sum_asm(&self, comment: &str, sum_cmd: &str) -> Asm {
-        let raw_lines = self.asm_reader.sum();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
-
+    fn gen_sum_asm(&self, source: &SourceLine, sum_cmd: &str) -> Result<Asm, Diagnostic> {
         // set sum command
-        asm.set_line(7, sum_cmd.to_string());
-        asm
-    }
+        let raw_lines = self
+            .fill("sum.asm", &self.asm_reader.sum, &[("SUM_COMMAND", sum_cmd)])
+            .map_err(invalid(source))?;
 
-    fn gen_cmp_asm(&mut self, comment: &str, compare_cmd: &str) -> Asm {
-        let raw_lines = self.asm_reader.cmp();
-
-        let mut asm = Asm {
-            comment: format!("//{}", comment),
-            lines: raw_lines,
-        };
+        Ok(Asm::new(source, raw_lines))
+    }
 
+    fn gen_cmp_asm(&mut self, source: &SourceLine, compare_cmd: &str) -> Result<Asm, Diagnostic> {
         // create labels for current asm set
         let true_lbl = format!("TRUE_{}", self.next_lbl_idx());
         let end_lbl = format!("END_{}", self.next_lbl_idx());
 
-        // set labels in asm
-        // true label
-        asm.set_line(15, format!("({true_lbl})"));
-        // end label
-        asm.set_line(19, format!("({end_lbl})"));
+        // set labels, conditional jumps and the command to compare
+        let raw_lines = self
+            .fill(
+                "cmp.asm",
+                &self.asm_reader.cmp,
+                &[
+                    ("TRUE_LABEL", &true_lbl),
+                    ("END_LABEL", &end_lbl),
+                    ("COMPARE_COMMAND", compare_cmd),
+                ],
+            )
+            .map_err(invalid(source))?;
+
+        Ok(Asm::new(source, raw_lines))
+    }
+
+    // templates are checked for their placeholders and instructions when
+    // loaded, the values filled in are checked here
+    fn fill(
+        &self,
+        template: &str,
+        lines: &[String],
+        values: &[(&str, &str)],
+    ) -> Result<Vec<Instruction>, TemplateError> {
+        template::fill(template, lines, values)?
+            .iter()
+            .map(|line| {
+                Instruction::parse(line).map_err(|err| {
+                    TemplateError::new(template, "", TemplateErrorKind::Invalid(err))
+                })
+            })
+            .collect()
+    }
+
+    // label names are scoped to the enclosing function, functionName$label,
+    // labels outside any function keep their name
+    fn scoped_label(&self, label: &str) -> String {
+        match &self.current_fn {
+            Some(func) => format!("{func}${label}"),
+            None => label.to_string(),
+        }
+    }
 
-        // set command to compare
-        asm.set_line(9, compare_cmd.to_string());
+    // {callerFunction}$ret.{n}, calls outside a function use the filename
+    fn next_ret_addr(&mut self) -> String {
+        let caller = self.current_fn.as_ref().unwrap_or(&self.filename).clone();
 
-        // set conditional jumps
-        asm.set_line(8, format!("@{true_lbl}"));
-        asm.set_line(13, format!("@{end_lbl}"));
+        let idx = self.ret_idx.entry(caller.clone()).or_insert(0);
+        *idx += 1;
 
-        asm
+        format!("{caller}$ret.{idx}")
     }
 
     fn next_lbl_idx(&mut self) -> i32 {
@@ -459,6 +516,88 @@ impl AsmGen {
     }
 }
 
+// a template that cannot be filled with the values of a command, points at
+// the name of the command when it has one
+fn invalid(source: &SourceLine) -> impl Fn(TemplateError) -> Diagnostic + '_ {
+    move |err| {
+        let nth = match source.command {
+            VmCommand::Function { .. }
+            | VmCommand::Call { .. }
+            | VmCommand::Label(_)
+            | VmCommand::Goto(_)
+            | VmCommand::IfGoto(_) => 1,
+            _ => 0,
+        };
+
+        match err.kind {
+            TemplateErrorKind::Invalid(err) => source.diagnostic(nth, &err.expected),
+            _ => source.diagnostic(nth, &err.to_string()),
+        }
+    }
+}
+
+// asm templates compiled into the binary, keyed by template filename,
+// with the placeholders filled by AsmGen
+const TEMPLATES: [(&str, &str, &[&str]); 16] = [
+    (
+        "call.asm",
+        include_str!("asm/call.asm"),
+        &["RET_ADD", "ARG_OFFSET", "FUCNTION_NAME", "SET_LABEL"],
+    ),
+    (
+        "cmp.asm",
+        include_str!("asm/cmp.asm"),
+        &["TRUE_LABEL", "COMPARE_COMMAND", "END_LABEL"],
+    ),
+    ("func.asm", include_str!("asm/func.asm"), &["FUNC_LABEL"]),
+    ("if_goto.asm", include_str!("asm/if_goto.asm"), &["LABEL"]),
+    ("init.asm", include_str!("asm/init.asm"), &["STACK_BASE"]),
+    ("neg.asm", include_str!("asm/neg.asm"), &["NEG_COMMAND"]),
+    (
+        "pop_mem_seg.asm",
+        include_str!("asm/pop_mem_seg.asm"),
+        &["MEM_SEG_INDEX", "MEM_SEG_LABEL"],
+    ),
+    (
+        "pop_ptr.asm",
+        include_str!("asm/pop_ptr.asm"),
+        &["MEM_SEG_LBL"],
+    ),
+    (
+        "pop_static_temp.asm",
+        include_str!("asm/pop_static_temp.asm"),
+        &["MEM_SEG_LABEL"],
+    ),
+    (
+        "push_const.asm",
+        include_str!("asm/push_const.asm"),
+        &["CONST_VAL"],
+    ),
+    (
+        "push_lcl.asm",
+        include_str!("asm/push_lcl.asm"),
+        &["LCL_OFFSET"],
+    ),
+    (
+        "push_mem_seg.asm",
+        include_str!("asm/push_mem_seg.asm"),
+        &["OFFSET_INDEX", "MEM_SEG_LABEL"],
+    ),
+    (
+        "push_ptr.asm",
+        include_str!("asm/push_ptr.asm"),
+        &["MEM_SEG_LBL"],
+    ),
+    (
+        "push_static_temp.asm",
+        include_str!("asm/push_static_temp.asm"),
+        &["ADDR_LBL"],
+    ),
+    ("return.asm", include_str!("asm/return.asm"), &[]),
+    ("sum.asm", include_str!("asm/sum.asm"), &["SUM_COMMAND"]),
+];
+
+#[derive(Clone, Debug)]
 pub struct AsmReader {
     pub call: Vec<String>,
     pub cmp: Vec<String>,
@@ -479,25 +618,37 @@ pub struct AsmReader {
 }
 
 impl AsmReader {
+    // templates embedded in the binary
     pub fn new() -> Self {
-        Self {
-            call: Self::read_asm_source("call.asm"),
-            cmp: Self::read_asm_source("cmp.asm"),
-            func: Self::read_asm_source("func.asm"),
-            if_goto: Self::read_asm_source("if_goto.asm"),
-            init: Self::read_asm_source("init.asm"),
-            neg: Self::read_asm_source("neg.asm"),
-            pop_mem_seg: Self::read_asm_source("pop_mem_seg.asm"),
-            pop_ptr: Self::read_asm_source("pop_ptr.asm"),
-            pop_static_temp: Self::read_asm_source("pop_static_temp.asm"),
-            push_const: Self::read_asm_source("push_const.asm"),
-            push_lcl: Self::read_asm_source("push_lcl.asm"),
-            push_mem_seg: Self::read_asm_source("push_mem_seg.asm"),
-            push_ptr: Self::read_asm_source("push_ptr.asm"),
-            push_static_temp: Self::read_asm_source("push_static_temp.asm"),
-            ret: Self::read_asm_source("return.asm"),
-            sum: Self::read_asm_source("sum.asm"),
-        }
+        // embedded templates never touch the file system
+        Self::load(None).unwrap()
+    }
+
+    // templates found in template_dir override the embedded ones,
+    // any template missing from the directory falls back to the embedded one
+    pub fn from_dir(template_dir: impl AsRef<Path>) -> error::Result<Self> {
+        Self::load(Some(template_dir.as_ref()))
+    }
+
+    fn load(template_dir: Option<&Path>) -> error::Result<Self> {
+        Ok(Self {
+            call: Self::read_asm_source("call.asm", template_dir)?,
+            cmp: Self::read_asm_source("cmp.asm", template_dir)?,
+            func: Self::read_asm_source("func.asm", template_dir)?,
+            if_goto: Self::read_asm_source("if_goto.asm", template_dir)?,
+            init: Self::read_asm_source("init.asm", template_dir)?,
+            neg: Self::read_asm_source("neg.asm", template_dir)?,
+            pop_mem_seg: Self::read_asm_source("pop_mem_seg.asm", template_dir)?,
+            pop_ptr: Self::read_asm_source("pop_ptr.asm", template_dir)?,
+            pop_static_temp: Self::read_asm_source("pop_static_temp.asm", template_dir)?,
+            push_const: Self::read_asm_source("push_const.asm", template_dir)?,
+            push_lcl: Self::read_asm_source("push_lcl.asm", template_dir)?,
+            push_mem_seg: Self::read_asm_source("push_mem_seg.asm", template_dir)?,
+            push_ptr: Self::read_asm_source("push_ptr.asm", template_dir)?,
+            push_static_temp: Self::read_asm_source("push_static_temp.asm", template_dir)?,
+            ret: Self::read_asm_source("return.asm", template_dir)?,
+            sum: Self::read_asm_source("sum.asm", template_dir)?,
+        })
     }
 
     pub fn call(&self) -> Vec<String> {
@@ -551,23 +702,71 @@ impl AsmReader {
         self.sum.clone()
     }
 
-    fn read_asm_source(filename: &str) -> Vec<String> {
-        let asm_dir = "src/asm".to_string();
-        let path = format!("{}/{}", asm_dir, filename);
-        LineParser::parse_lines(&path).unwrap()
+    // every placeholder of the template must be one AsmGen fills, and the
+    // other way round
+    fn read_asm_source(filename: &str, template_dir: Option<&Path>) -> error::Result<Vec<String>> {
+        let (_, source, placeholders) = TEMPLATES
+            .iter()
+            .find(|(name, _, _)| *name == filename)
+            .expect("every template is embedded");
+
+        let lines = match template_dir.map(|dir| dir.join(filename)) {
+            Some(path) if path.exists() => LineParser::parse_lines(&path)?,
+            _ => LineParser::parse_source(source),
+        };
+
+        template::check(filename, &lines, placeholders)?;
+        template::check_instructions(filename, &lines)?;
+
+        Ok(lines)
     }
+}
 
-    // fn get_filepath(&self, filename: &str) -> String {
-    //     // let path = self.asm_dir.as_path();
-    //     // let path = self.asm_dir.as_path();
-    //     let asm_dir = &self.asm_dir;
-    //     format!("{}/{}", asm_dir, filename)
-    // }
+impl Default for AsmReader {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::*;
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::line::Location;
+    use crate::parser::Parser;
+    use crate::test_dir;
+    use std::fs;
+
+    fn source_line(source: &str) -> SourceLine {
+        let location = Location::new("Test.vm", 1, source);
+        let command = Parser::parse_command(&location).unwrap();
+
+        SourceLine::new(source, command, location)
+    }
+
+    // run if-goto with cond on top of the stack, returns true if the jump was taken
+    fn run_if_goto(cond: i16) -> bool {
+        let mut asm_gen = AsmGen::new("Test", AsmReader::new());
+        let asm = asm_gen
+            .gen_if_asm(&source_line("if-goto TRUE"), "TRUE")
+            .unwrap();
+
+        // R5 is only set when falling through to the false branch
+        let mut lines: Vec<String> = asm.instructions.iter().map(|i| i.to_string()).collect();
+        lines.extend(["@R5", "M=1", "(TRUE)"].map(String::from));
+
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        let mut emulator = Emulator::load_lines(&lines).unwrap();
+        emulator.ram[0] = 257;
+        emulator.ram[256] = cond;
+
+        emulator.run(100).unwrap();
+
+        // condition is popped from the stack
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.ram[0], 256);
+        emulator.ram[5] == 0
+    }
 
     #[test]
     fn test_asm_gen_add() {
@@ -578,4 +777,162 @@ mod test {
         // assert_eq!(asm.comment, "add numbers together");
         // println!("{:?}", asm);
     }
+
+    #[test]
+    fn test_if_goto_single_jump() {
+        let mut asm_gen = AsmGen::new("Test", AsmReader::new());
+        let asm = asm_gen
+            .gen_if_asm(&source_line("if-goto END"), "END")
+            .unwrap();
+
+        let jumps: Vec<String> = asm
+            .instructions
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .filter(|line| line.contains(';'))
+            .collect();
+
+        assert_eq!(jumps, vec!["D;JNE"]);
+
+        // the jump goes to the label loaded just before it
+        let lines: Vec<String> = asm.instructions.iter().map(|i| i.to_string()).collect();
+        let jump = lines.iter().position(|line| line == "D;JNE").unwrap();
+        assert_eq!(lines[jump - 1], "@END");
+    }
+
+    #[test]
+    fn test_invalid_symbols_are_diagnostics() {
+        let mut asm_gen = AsmGen::new("Test", AsmReader::new());
+
+        // names the parser did not check are reported, not written out
+        let source = source_line("call Math.max 2");
+        let diagnostic = asm_gen.gen_call_asm(&source, "9lives", 0).unwrap_err();
+        assert_eq!(
+            (diagnostic.column, diagnostic.token.as_str()),
+            (6, "Math.max")
+        );
+
+        let source = source_line("goto END");
+        assert!(asm_gen.gen_goto_asm(&source, "a-b").is_err());
+        assert!(asm_gen.gen_label_asm(&source, "1").is_err());
+        assert!(asm_gen.gen_goto_asm(&source, "END").is_ok());
+    }
+
+    #[test]
+    fn test_if_goto_zero_falls_through() {
+        assert!(!run_if_goto(0));
+    }
+
+    #[test]
+    fn test_if_goto_positive_jumps() {
+        assert!(run_if_goto(1));
+        assert!(run_if_goto(42));
+        assert!(run_if_goto(i16::MAX));
+    }
+
+    #[test]
+    fn test_if_goto_negative_jumps() {
+        assert!(run_if_goto(-7));
+        assert!(run_if_goto(i16::MIN));
+    }
+
+    #[test]
+    fn test_if_goto_true_jumps() {
+        // -1 is the value pushed by eq, gt and lt for true
+        assert!(run_if_goto(-1));
+    }
+
+    #[test]
+    fn test_return_labels_named_after_caller() {
+        let mut asm_gen = AsmGen::new("Main", AsmReader::new());
+        let call = |asm_gen: &mut AsmGen| {
+            let asm = asm_gen
+                .gen_call_asm(&source_line("call Math.max 2"), "Math.max", 2)
+                .unwrap();
+            let lines: Vec<String> = asm.instructions.iter().map(|i| i.to_string()).collect();
+
+            // jump to the function, the return label follows the jump
+            let jump = lines.iter().position(|line| line == "0;JMP").unwrap();
+            assert_eq!(lines[jump - 1], "@Math.max");
+            lines[jump + 1].clone()
+        };
+
+        // outside a function the filename stands in for the caller
+        assert_eq!(call(&mut asm_gen), "(Main$ret.1)");
+
+        asm_gen
+            .gen_func_asm(&source_line("function Main.main 0"), "Main.main", 0)
+            .unwrap();
+        assert_eq!(call(&mut asm_gen), "(Main.main$ret.1)");
+        assert_eq!(call(&mut asm_gen), "(Main.main$ret.2)");
+
+        asm_gen
+            .gen_func_asm(&source_line("function Main.run 0"), "Main.run", 0)
+            .unwrap();
+        assert_eq!(call(&mut asm_gen), "(Main.run$ret.1)");
+    }
+
+    #[test]
+    fn test_asm_reader_embedded() {
+        let reader = AsmReader::new();
+
+        assert_eq!(reader.call.len(), 50);
+        assert_eq!(reader.push_const[0], "@$CONST_VAL");
+        assert_eq!(reader.init, vec!["@$STACK_BASE", "D=A", "@SP", "M=D"]);
+    }
+
+    #[test]
+    fn test_asm_reader_template_dir() -> error::Result<()> {
+        let dir = test_dir("template_dir");
+        fs::write(
+            dir.join("init.asm"),
+            "@$STACK_BASE\nD=A+1 // one above the stack base\n@SP\nM=D\n",
+        )?;
+
+        let reader = AsmReader::from_dir(&dir)?;
+
+        // overridden template is read from the directory, the rest are embedded
+        assert_eq!(reader.init[1], "D=A+1");
+        assert_eq!(reader.call, AsmReader::new().call);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_template_placeholders() -> error::Result<()> {
+        let dir = test_dir("placeholders");
+
+        // placeholders are filled by name wherever they are in the template
+        fs::write(
+            dir.join("push_const.asm"),
+            "D=0 // extra line\n@$CONST_VAL\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n",
+        )?;
+        let asm_gen = AsmGen::new("Test", AsmReader::from_dir(&dir)?);
+        let asm = asm_gen
+            .gen_push_const(&source_line("push constant 7"), 7)
+            .unwrap();
+        let lines: Vec<String> = asm.instructions[..3]
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert_eq!(lines, ["D=0", "@7", "D=A"]);
+
+        fs::write(dir.join("push_const.asm"), "@$VALUE\nD=A\n")?;
+        let err = AsmReader::from_dir(&dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: template push_const.asm: unknown placeholder `$VALUE`"
+        );
+
+        fs::write(dir.join("push_const.asm"), "@7\nD=A\n")?;
+        let err = AsmReader::from_dir(&dir).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error: template push_const.asm: missing placeholder `$CONST_VAL`"
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
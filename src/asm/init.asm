@@ -1,4 +1,4 @@
-@256
+@$STACK_BASE
 D=A
 @SP
 M=D
\ No newline at end of file
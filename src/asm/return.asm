@@ -1,3 +1,16 @@
+// ENDFRAME ADDR
+@LCL
+D=M // get mem address of LCL
+@R13 // endframe
+M=D // endframe tmp var
+
+// RETURN ADDR
+@5 // gets the return address
+A=D-A
+D=M
+@R14 // retAddr
+M=D // set retAddr tmp before ARG[0] is overwritten, zero arg calls share the slot
+
 // POP to return
 @SP
 M=M-1 // get last value on stack address 
@@ -13,56 +26,34 @@ D=M
 @SP // SP = ARG + 1, repositions SP of the caller
 M=D+1 // repositions SP of the caller
 
-// ENDFRAME ADDR
-@LCL
-D=M // get mem address of LCL
-@R13 // endframe
-M=D // endframe tmp var
-
-// RETURN ADDR
-@5 // gets the return address
-D=A
+// THAT
 @R13
-D=M-D
-A=D
+AM=M-1
 D=M
-@R14 // retAddr
-M=D // set retAddr tmp 
+@THAT // restores THAT of the caller
+M=D // THAT = *(endFrame – 1)
 
-// LCL
-@4 // restores LCL of the caller
-D=A
+// THIS
 @R13
-A=M-D
+AM=M-1
 D=M
-@LCL
-M=D // LCL = *(endFrame – 4)
+@THIS // restores THIS of the caller
+M=D // THIS = *(endFrame – 2)
 
 // ARG
-@3 // restores ARG of the caller
-D=A
 @R13
-A=M-D
+AM=M-1
 D=M
 @ARG 
 M=D // ARG = *(endFrame – 3)
 
-// THIS
-@2 // restores THIS of the caller
-D=A
-@R13
-A=M-D
-D=M
-@THIS
-M=D // THIS = *(endFrame – 2)
-
-// THAT
+// LCL
 @R13
-A=M-1
+AM=M-1
 D=M
-@THAT // restores THAT of the caller
-M=D // THAT = *(endFrame – 1)
+@LCL
+M=D // LCL = *(endFrame – 4)
 
 @R14
 A=M
-0;JMP // jump to return address
\ No newline at end of file
+0;JMP // jump to return address
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::asm::Asm;
+use crate::instruction::{Instruction, Value};
+
+// first RAM address handed out to variables, eg. static Foo.3
+const VARIABLE_BASE: u16 = 16;
+
+// an asm line that cannot be assembled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub instruction: String,
+    pub expected: String,
+}
+
+impl AsmError {
+    pub fn new(instruction: &str, expected: &str) -> Self {
+        Self {
+            instruction: instruction.to_string(),
+            expected: expected.to_string(),
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid instruction `{}`, expected {}",
+            self.instruction, self.expected
+        )
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// labels, predefined symbols and variables mapped to their address
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, u16>,
+    next_variable: u16,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        let mut symbols: HashMap<String, u16> = HashMap::new();
+
+        for (addr, reg) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+            symbols.insert(reg.to_string(), addr as u16);
+        }
+        for addr in 0..16 {
+            symbols.insert(format!("R{addr}"), addr);
+        }
+        symbols.insert("SCREEN".to_string(), 16384);
+        symbols.insert("KBD".to_string(), 24576);
+
+        Self {
+            symbols,
+            next_variable: VARIABLE_BASE,
+        }
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<u16> {
+        self.symbols.get(symbol).copied()
+    }
+
+    pub fn add_label(&mut self, label: &str, addr: u16) {
+        self.symbols.insert(label.to_string(), addr);
+    }
+
+    // address of a known symbol, unknown symbols are variables allocated from 16
+    pub fn resolve(&mut self, symbol: &str) -> u16 {
+        if let Some(addr) = self.get(symbol) {
+            return addr;
+        }
+
+        let addr = self.next_variable;
+        self.symbols.insert(symbol.to_string(), addr);
+        self.next_variable += 1;
+        addr
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Assembler {
+    pub symbols: SymbolTable,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self {
+            symbols: SymbolTable::new(),
+        }
+    }
+
+    // assemble the asm generated by gen_asm into Hack machine words
+    pub fn assemble(&mut self, asm: &[Asm]) -> Result<Vec<u16>, AsmError> {
+        let instructions: Vec<&Instruction> =
+            asm.iter().flat_map(|asm| asm.instructions.iter()).collect();
+
+        self.assemble_instructions(&instructions)
+    }
+
+    // asm text, one instruction per line with optional comments
+    pub fn assemble_lines(&mut self, lines: &[&str]) -> Result<Vec<u16>, AsmError> {
+        let instructions = parse_lines(lines)?;
+
+        self.assemble_instructions(&instructions.iter().collect::<Vec<_>>())
+    }
+
+    fn assemble_instructions(
+        &mut self,
+        instructions: &[&Instruction],
+    ) -> Result<Vec<u16>, AsmError> {
+        define_labels(&mut self.symbols, instructions)?;
+
+        // second pass, encode instructions and allocate variables
+        let mut words = vec![];
+        for instruction in instructions {
+            let word = match instruction {
+                Instruction::A(Value::Literal(value)) => *value,
+                Instruction::A(Value::Symbol(symbol)) => self.symbols.resolve(symbol),
+                Instruction::C { dest, comp, jump } => {
+                    0b111 << 13 | comp.bits() << 6 | dest.bits() << 3 | jump.bits()
+                }
+                Instruction::Label(_) | Instruction::Comment(_) => continue,
+            };
+            words.push(word);
+        }
+
+        Ok(words)
+    }
+}
+
+// parse asm text, comments and blank lines are skipped
+pub fn parse_lines(lines: &[&str]) -> Result<Vec<Instruction>, AsmError> {
+    lines
+        .iter()
+        .map(|line| strip(line))
+        .filter(|line| !line.is_empty())
+        .map(Instruction::parse)
+        .collect()
+}
+
+// first pass, labels point at the next instruction and are defined once
+fn define_labels(symbols: &mut SymbolTable, instructions: &[&Instruction]) -> Result<(), AsmError> {
+    let mut rom_len = 0;
+    for instruction in instructions {
+        match instruction {
+            Instruction::Label(label) => {
+                if symbols.get(label).is_some() {
+                    return Err(AsmError::new(
+                        &instruction.to_string(),
+                        "a label defined only once",
+                    ));
+                }
+                symbols.add_label(label, rom_len as u16);
+            }
+            instruction if instruction.is_code() => rom_len += 1,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// check the generated asm defines every label once, without assembling it
+pub fn check_labels(asm: &[Asm]) -> Result<(), AsmError> {
+    let instructions: Vec<&Instruction> =
+        asm.iter().flat_map(|asm| asm.instructions.iter()).collect();
+
+    define_labels(&mut SymbolTable::new(), &instructions)
+}
+
+// .hack text, one 16 bit binary word per line
+pub fn to_hack(words: &[u16]) -> String {
+    words.iter().map(|word| format!("{word:016b}\n")).collect()
+}
+
+// remove comments and whitespace from an asm line
+fn strip(line: &str) -> &str {
+    line.split("//").next().unwrap().trim()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assemble(lines: &[&str]) -> Vec<u16> {
+        Assembler::new().assemble_lines(lines).unwrap()
+    }
+
+    #[test]
+    fn test_encode_instructions() {
+        let words = assemble(&[
+            "@2", "D=A", "@3", "D=D+A", "@0", "M=D", "AM=M-1", "0;JMP", "D;JNE",
+        ]);
+
+        assert_eq!(
+            to_hack(&words),
+            [
+                "0000000000000010",
+                "1110110000010000",
+                "0000000000000011",
+                "1110000010010000",
+                "0000000000000000",
+                "1110001100001000",
+                "1111110010101000",
+                "1110101010000111",
+                "1110001100000101",
+            ]
+            .map(|line| format!("{line}\n"))
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut assembler = Assembler::new();
+        let words = assembler
+            .assemble_lines(&[
+                "(START)",
+                "@i",
+                "M=1 // comment",
+                "@LOOP",
+                "(LOOP)",
+                "@j",
+                "@i",
+                "@R13",
+                "@KBD",
+                "@START",
+            ])
+            .unwrap();
+
+        assert_eq!(words, vec![16, 0b1110111111001000, 3, 17, 16, 13, 24576, 0]);
+        assert_eq!(assembler.symbols.get("LOOP"), Some(3));
+        assert_eq!(assembler.symbols.get("j"), Some(17));
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        for line in ["M=–D", "X=D", "DD=1", "D;JXX", "@40000", "(OPEN"] {
+            assert!(Assembler::new().assemble_lines(&[line]).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let err = Assembler::new()
+            .assemble_lines(&["(TRUE_1)", "@TRUE_1", "(TRUE_1)"])
+            .unwrap_err();
+        assert_eq!(err.instruction, "(TRUE_1)");
+        assert_eq!(err.expected, "a label defined only once");
+
+        let asm = |label: &str| Asm {
+            comment: "//eq".to_string(),
+            instructions: parse_lines(&[&format!("({label})"), "0;JMP"]).unwrap(),
+        };
+        assert!(check_labels(&[asm("Main.TRUE_1"), asm("Foo.TRUE_1")]).is_ok());
+        assert!(check_labels(&[asm("TRUE_1"), asm("TRUE_1")]).is_err());
+    }
+}
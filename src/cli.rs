@@ -0,0 +1,437 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "usage: hvm-translator [translate] [options] <file.vm | directory | ->...
+       hvm-translator check [options] <file.vm | directory | ->...
+       hvm-translator run [options] [--cycles <n>] <file.vm | directory | ->...
+       hvm-translator asm [-o <file>] <file.asm | ->
+       hvm-translator test [--template-dir <dir>] <script.tst>";
+
+pub const HELP: &str = "
+commands:
+  translate                  translate VM code to Hack assembly, the default command
+  check                      parse and validate VM code without writing any output
+  run                        translate and execute VM code on the CPU emulator, print the stack
+  asm                        assemble a .asm file into Hack machine code
+  test                       run a CPU emulator .tst script and compare with its .cmp file
+
+options:
+  --template-dir <dir>       read asm templates from dir, missing ones are built in
+  --allow-unknown            translate unknown commands as //UNKOWN comments
+  --emit asm,hack            output formats, asm by default
+  -o, --output <file>        output file, - for stdout
+  --module <name>            module of VM code read from stdin, Stdin by default
+  --order <Module,...>       modules translated first, in this order
+  -L, --lib-path <dir>       search dir for the functions the program calls but does not define
+  --bootstrap                always emit the bootstrap
+  --no-bootstrap             never emit the bootstrap
+  --entry <function>         function called by the bootstrap, Sys.init by default
+  --sp <n>                   initial SP set by the bootstrap, 256 by default
+  --cycles <n>               instructions executed by run, 1000000 by default
+  -q, --quiet                print errors only
+  -v, --verbose              print the files read and written
+  -h, --help                 print this help
+  --version                  print the version
+
+exit status:
+  0  success
+  1  a test or run failed
+  2  usage error
+  3  parse error in VM code, asm or a template
+  4  I/O error";
+
+// what the translator was asked to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Translate,
+    // parse, link and validate without writing output
+    Check,
+    // execute the translated program on the CPU emulator
+    Run,
+    // assemble a .asm file into .hack
+    Asm,
+    // run a .tst script and compare its output with the .cmp table
+    Test,
+    Help,
+    Version,
+}
+
+// how much is printed besides the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// output formats written by the translator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Asm,
+    Hack,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    // files and directories of the program, the first one names the output
+    pub inputs: Vec<PathBuf>,
+    pub template_dir: Option<PathBuf>,
+    // keep translating unknown commands as //UNKOWN comments
+    pub allow_unknown: bool,
+    pub emit: Vec<Emit>,
+    // output file instead of the name derived from the input, - for stdout
+    pub output: Option<PathBuf>,
+    // module naming the static variables of VM code read from stdin
+    pub module: String,
+    // modules translated first, in this order
+    pub order: Vec<String>,
+    // searched for the functions the program calls but does not define
+    pub lib_paths: Vec<PathBuf>,
+    // None emits the bootstrap when the program defines the entry function
+    pub bootstrap: Option<bool>,
+    // function called by the bootstrap
+    pub entry: String,
+    // initial SP set by the bootstrap
+    pub stack_base: u16,
+    // instructions executed by run
+    pub cycles: usize,
+    pub verbosity: Verbosity,
+}
+
+impl Default for Cli {
+    fn default() -> Self {
+        Self {
+            command: Command::Translate,
+            inputs: vec![],
+            template_dir: None,
+            allow_unknown: false,
+            emit: vec![Emit::Asm],
+            output: None,
+            module: "Stdin".to_string(),
+            order: vec![],
+            lib_paths: vec![],
+            bootstrap: None,
+            entry: "Sys.init".to_string(),
+            stack_base: 256,
+            cycles: 1_000_000,
+            verbosity: Verbosity::Normal,
+        }
+    }
+}
+
+impl Cli {
+    // parse command line args, first arg is expected to be the program name,
+    // paths may be any OS string while options and their values are UTF-8
+    pub fn parse(args: &[OsString]) -> Result<Self, String> {
+        let mut cli = Cli::default();
+
+        let mut args = args.iter().skip(1).peekable();
+
+        let command = match args.peek().and_then(|arg| arg.to_str()) {
+            Some("translate") => Some(Command::Translate),
+            Some("check") => Some(Command::Check),
+            Some("run") => Some(Command::Run),
+            Some("asm") => Some(Command::Asm),
+            Some("test") => Some(Command::Test),
+            _ => None,
+        };
+        if let Some(command) = command {
+            cli.command = command;
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.to_str() {
+                Some("--template-dir") => {
+                    let dir = args
+                        .next()
+                        .ok_or_else(|| "--template-dir requires a directory".to_string())?;
+                    cli.template_dir = Some(PathBuf::from(dir));
+                }
+                Some("--allow-unknown") => cli.allow_unknown = true,
+                Some("--emit") => {
+                    let formats = Self::text(args.next(), "--emit requires asm, hack or both")?;
+                    cli.emit = Self::parse_emit(formats)?;
+                }
+                Some(opt @ ("-o" | "--output")) => {
+                    let output = args
+                        .next()
+                        .ok_or_else(|| format!("{opt} requires a file"))?;
+                    cli.output = Some(PathBuf::from(output));
+                }
+                Some("--module") => {
+                    cli.module = Self::text(args.next(), "--module requires a name")?.to_string();
+                }
+                Some("--order") => {
+                    let modules = Self::text(args.next(), "--order requires a list of modules")?;
+                    cli.order = modules
+                        .split(',')
+                        .map(|module| module.to_string())
+                        .collect();
+                }
+                Some(opt @ ("-L" | "--lib-path")) => {
+                    let dir = args
+                        .next()
+                        .ok_or_else(|| format!("{opt} requires a directory"))?;
+                    cli.lib_paths.push(PathBuf::from(dir));
+                }
+                Some("--bootstrap") => cli.bootstrap = Some(true),
+                Some("--no-bootstrap") => cli.bootstrap = Some(false),
+                Some("--entry") => {
+                    cli.entry = Self::text(args.next(), "--entry requires a function")?.to_string();
+                }
+                Some("--sp") => {
+                    let sp = Self::text(args.next(), "--sp requires an address")?;
+                    cli.stack_base = match sp.parse::<u16>() {
+                        Ok(sp) if sp <= 32767 => sp,
+                        _ => {
+                            return Err(format!("--sp {sp} is not an address between 0 and 32767"))
+                        }
+                    };
+                }
+                Some("--cycles") => {
+                    let cycles = Self::text(args.next(), "--cycles requires a number")?;
+                    cli.cycles = cycles
+                        .parse()
+                        .map_err(|_| format!("--cycles {cycles} is not a number"))?;
+                }
+                Some("-q" | "--quiet") => cli.verbosity = Verbosity::Quiet,
+                Some("-v" | "--verbose") => cli.verbosity = Verbosity::Verbose,
+                // help and version ignore every other argument
+                Some("-h" | "--help") => return Ok(Self::with_command(Command::Help)),
+                Some("--version") => return Ok(Self::with_command(Command::Version)),
+                Some(opt) if opt.starts_with('-') && opt != "-" => {
+                    return Err(format!("unknown option {opt}"))
+                }
+                _ if cli.single_input() && !cli.inputs.is_empty() => {
+                    return Err(format!("unexpected argument {}", arg.to_string_lossy()))
+                }
+                _ => cli.inputs.push(PathBuf::from(arg)),
+            }
+        }
+
+        if cli.inputs.is_empty() {
+            return Err("please provide filename".to_string());
+        }
+
+        Ok(cli)
+    }
+
+    fn with_command(command: Command) -> Self {
+        Self {
+            command,
+            ..Self::default()
+        }
+    }
+
+    // test scripts and asm files are handled one at a time
+    fn single_input(&self) -> bool {
+        matches!(self.command, Command::Test | Command::Asm)
+    }
+
+    // the UTF-8 value of an option
+    fn text<'a>(value: Option<&'a OsString>, missing: &str) -> Result<&'a str, String> {
+        let value = value.ok_or_else(|| missing.to_string())?;
+
+        value
+            .to_str()
+            .ok_or_else(|| format!("{} is not valid UTF-8", value.to_string_lossy()))
+    }
+
+    // - reads VM code from stdin and writes to stdout
+    pub fn is_stdin(&self) -> bool {
+        self.inputs.iter().any(|input| input == Path::new("-"))
+    }
+
+    // comma separated list of output formats, eg. asm,hack
+    fn parse_emit(formats: &str) -> Result<Vec<Emit>, String> {
+        let mut emit = vec![];
+
+        for format in formats.split(',') {
+            let format = match format {
+                "asm" => Emit::Asm,
+                "hack" => Emit::Hack,
+                _ => {
+                    return Err(format!(
+                        "unknown --emit format {format}, expected asm or hack"
+                    ))
+                }
+            };
+            if !emit.contains(&format) {
+                emit.push(format);
+            }
+        }
+
+        Ok(emit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_template_dir() {
+        let cli = Cli::parse(&args(&["hvm", "--template-dir", "tpl", "Main.vm"])).unwrap();
+
+        assert_eq!(cli.inputs, vec![Path::new("Main.vm")]);
+        assert_eq!(cli.template_dir.as_deref(), Some(Path::new("tpl")));
+        assert!(!cli.allow_unknown);
+    }
+
+    #[test]
+    fn test_parse_allow_unknown() {
+        let cli = Cli::parse(&args(&["hvm", "Main.vm", "--allow-unknown"])).unwrap();
+
+        assert_eq!(cli.inputs, vec![Path::new("Main.vm")]);
+        assert!(cli.allow_unknown);
+    }
+
+    #[test]
+    fn test_parse_emit() {
+        let cli = Cli::parse(&args(&["hvm", "Main.vm"])).unwrap();
+        assert_eq!(cli.emit, vec![Emit::Asm]);
+
+        let cli = Cli::parse(&args(&["hvm", "--emit", "hack", "Main.vm"])).unwrap();
+        assert_eq!(cli.emit, vec![Emit::Hack]);
+
+        let cli = Cli::parse(&args(&["hvm", "--emit", "hack,asm", "Main.vm"])).unwrap();
+        assert_eq!(cli.emit, vec![Emit::Hack, Emit::Asm]);
+
+        assert!(Cli::parse(&args(&["hvm", "--emit", "bin", "Main.vm"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_test_command() {
+        let cli = Cli::parse(&args(&["hvm", "test", "SimpleAdd.tst"])).unwrap();
+        assert_eq!(cli.command, Command::Test);
+        assert_eq!(cli.inputs, vec![Path::new("SimpleAdd.tst")]);
+
+        let cli = Cli::parse(&args(&["hvm", "Main.vm"])).unwrap();
+        assert_eq!(cli.command, Command::Translate);
+
+        assert!(Cli::parse(&args(&["hvm", "test"])).is_err());
+        assert!(Cli::parse(&args(&["hvm", "test", "A.tst", "B.tst"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_stdin() {
+        let cli = Cli::parse(&args(&["hvm", "--module", "Main", "-"])).unwrap();
+        assert!(cli.is_stdin());
+        assert_eq!(cli.module, "Main");
+
+        let cli = Cli::parse(&args(&["hvm", "Main.vm"])).unwrap();
+        assert!(!cli.is_stdin());
+        assert_eq!(cli.module, "Stdin");
+    }
+
+    #[test]
+    fn test_parse_order() {
+        let cli = Cli::parse(&args(&["hvm", "--order", "Main,Math", "Prog"])).unwrap();
+        assert_eq!(cli.order, vec!["Main", "Math"]);
+
+        assert!(Cli::parse(&args(&["hvm", "Prog", "--order"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        let cli = Cli::parse(&args(&["hvm", "-o", "out/Prog.asm", "Prog"])).unwrap();
+        assert_eq!(cli.output.as_deref(), Some(Path::new("out/Prog.asm")));
+
+        let cli = Cli::parse(&args(&["hvm", "Prog", "--output", "-"])).unwrap();
+        assert_eq!(cli.output.as_deref(), Some(Path::new("-")));
+
+        assert!(Cli::parse(&args(&["hvm", "Prog", "-o"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_inputs_and_lib_paths() {
+        let cli = Cli::parse(&args(&[
+            "hvm",
+            "Prog",
+            "-L",
+            "os",
+            "Extra.vm",
+            "--lib-path",
+            "lib",
+        ]))
+        .unwrap();
+
+        assert_eq!(cli.inputs, vec![Path::new("Prog"), Path::new("Extra.vm")]);
+        assert_eq!(cli.lib_paths, vec![Path::new("os"), Path::new("lib")]);
+
+        assert!(Cli::parse(&args(&["hvm", "Prog", "-L"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_missing_input() {
+        assert!(Cli::parse(&args(&["hvm"])).is_err());
+        assert!(Cli::parse(&args(&["hvm", "Main.vm", "--template-dir"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_bootstrap() {
+        let cli = Cli::parse(&args(&["hvm", "Prog"])).unwrap();
+        assert_eq!(cli.bootstrap, None);
+        assert_eq!((cli.entry.as_str(), cli.stack_base), ("Sys.init", 256));
+
+        let cli = Cli::parse(&args(&[
+            "hvm",
+            "--bootstrap",
+            "--entry",
+            "Main.main",
+            "--sp",
+            "300",
+            "Prog",
+        ]))
+        .unwrap();
+        assert_eq!(cli.bootstrap, Some(true));
+        assert_eq!((cli.entry.as_str(), cli.stack_base), ("Main.main", 300));
+
+        let cli = Cli::parse(&args(&["hvm", "--no-bootstrap", "Prog"])).unwrap();
+        assert_eq!(cli.bootstrap, Some(false));
+
+        assert!(Cli::parse(&args(&["hvm", "--sp", "40000", "Prog"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        for (name, command) in [
+            ("translate", Command::Translate),
+            ("check", Command::Check),
+            ("run", Command::Run),
+            ("asm", Command::Asm),
+        ] {
+            let cli = Cli::parse(&args(&["hvm", name, "Prog"])).unwrap();
+            assert_eq!(cli.command, command);
+            assert_eq!(cli.inputs, vec![Path::new("Prog")]);
+        }
+
+        let cli = Cli::parse(&args(&["hvm", "run", "--cycles", "500", "Prog"])).unwrap();
+        assert_eq!(cli.cycles, 500);
+
+        assert!(Cli::parse(&args(&["hvm", "asm", "A.asm", "B.asm"])).is_err());
+        assert!(Cli::parse(&args(&["hvm", "-x", "Prog"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_help_and_verbosity() {
+        // help and version need no input
+        let cli = Cli::parse(&args(&["hvm", "check", "--help"])).unwrap();
+        assert_eq!(cli.command, Command::Help);
+        let cli = Cli::parse(&args(&["hvm", "--version"])).unwrap();
+        assert_eq!(cli.command, Command::Version);
+
+        let cli = Cli::parse(&args(&["hvm", "Prog"])).unwrap();
+        assert_eq!(cli.verbosity, Verbosity::Normal);
+        let cli = Cli::parse(&args(&["hvm", "-q", "Prog"])).unwrap();
+        assert_eq!(cli.verbosity, Verbosity::Quiet);
+        let cli = Cli::parse(&args(&["hvm", "check", "--verbose", "Prog"])).unwrap();
+        assert_eq!(cli.verbosity, Verbosity::Verbose);
+    }
+}
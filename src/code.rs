@@ -1,118 +1,320 @@
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::io::Result;
+use std::path::{Path, PathBuf};
 
-use crate::asm::{Asm, AsmGen};
-use crate::line::ArithOp;
-use crate::line::CommandType;
-use crate::line::MemSeg;
-use crate::line::SourceLine;
+use crate::asm::{Asm, AsmGen, AsmReader};
+use crate::assembler::to_hack;
+use crate::error::Diagnostic;
+use crate::line::{Op, Segment, SourceLine, VmCommand};
+use crate::template::TemplateError;
 
 pub struct Code {
     asm_gen: AsmGen,
+    // emit //UNKOWN comments for unknown commands instead of failing
+    pub allow_unknown: bool,
+    // non fatal diagnostics found while generating asm
+    pub warnings: Vec<Diagnostic>,
 }
 
 impl Code {
-    pub fn new(filename: &str) -> Self {
-        // let fn_slipt = filename.split('.').collect::<Vec<&str>>();
-        // let filename = fn_slipt.get(0).unwrap().to_string();
-        let asm_gen = AsmGen::new(filename).unwrap();
-
-        Self { asm_gen }
-    }
-
-    pub fn gen_init_asm(&mut self) -> Asm {
-        self.asm_gen.gen_init_asm()
-    }
-
-    // top level asm generator, calls private methods based on cmd type
-    pub fn gen_asm(&mut self, source: &SourceLine) -> Asm {
-        // get command type
-        match source.cmd_type {
-            CommandType::ARITHMETIC => self.gen_arith_asm(source),
-            CommandType::PUSH => self.gen_push_asm(source),
-            CommandType::POP => self.gen_pop_asm(source),
-            CommandType::IF => self.asm_gen.gen_if_asm(source),
-            CommandType::GOTO => self.asm_gen.gen_goto_asm(source),
-            CommandType::LABEL => self.asm_gen.gen_label_asm(source),
-            CommandType::CALL => self.asm_gen.gen_call_asm(source),
-            CommandType::FUNCTION => self.asm_gen.gen_func_asm(source),
-            CommandType::RETURN => self.asm_gen.gen_ret_asm(source),
-            // unknown cmd_type
-            _ => Asm::unkown(&source.source),
+    pub fn new(filename: &str, templates: &AsmReader) -> Self {
+        let asm_gen = AsmGen::new(filename, templates.clone());
+
+        Self {
+            asm_gen,
+            allow_unknown: false,
+            warnings: vec![],
+        }
+    }
+
+    // translate the next file with the same label allocator
+    pub fn set_filename(&mut self, filename: &str) {
+        self.asm_gen.set_filename(filename);
+    }
+
+    pub fn gen_init_asm(&mut self, entry: &str, stack_base: u16) -> Result<Asm, TemplateError> {
+        self.asm_gen.gen_init_asm(entry, stack_base)
+    }
+
+    // top level asm generator, calls private methods based on the command
+    pub fn gen_asm(&mut self, source: &SourceLine) -> Result<Asm, Diagnostic> {
+        match &source.command {
+            VmCommand::Arith(op) => self.gen_arith_asm(source, *op),
+            VmCommand::Push(segment, index) => self.gen_push_asm(source, *segment, *index),
+            VmCommand::Pop(segment, index) => self.gen_pop_asm(source, *segment, *index),
+            VmCommand::IfGoto(label) => {
+                self.check_label_scope(source);
+                self.asm_gen.gen_if_asm(source, label)
+            }
+            VmCommand::Goto(label) => {
+                self.check_label_scope(source);
+                self.asm_gen.gen_goto_asm(source, label)
+            }
+            VmCommand::Label(label) => {
+                self.check_label_scope(source);
+                self.asm_gen.gen_label_asm(source, label)
+            }
+            VmCommand::Call { name, n_args } => self.asm_gen.gen_call_asm(source, name, *n_args),
+            VmCommand::Function { name, n_locals } => {
+                self.asm_gen.gen_func_asm(source, name, *n_locals)
+            }
+            VmCommand::Return => self.asm_gen.gen_ret_asm(source),
+            // unknown command, segment or op
+            VmCommand::Unknown(diagnostic) => self.unknown(source, diagnostic.clone()),
+        }
+    }
+
+    // labels can only be scoped when used inside a function
+    fn check_label_scope(&mut self, source: &SourceLine) {
+        if self.asm_gen.current_fn.is_none() {
+            let warning = source
+                .diagnostic(1, "label to be used inside a function")
+                .into_warning();
+            self.warnings.push(warning);
+        }
+    }
+
+    // unknown commands are errors unless allow_unknown is set
+    fn unknown(&self, source: &SourceLine, diagnostic: Diagnostic) -> Result<Asm, Diagnostic> {
+        if self.allow_unknown {
+            Ok(Asm::unkown(&source.source))
+        } else {
+            Err(diagnostic)
         }
     }
 
-    fn gen_arith_asm(&mut self, source: &SourceLine) -> Asm {
+    fn unknown_index(&self, source: &SourceLine, expected: &str) -> Result<Asm, Diagnostic> {
+        self.unknown(source, source.diagnostic(2, expected))
+    }
+
+    fn gen_arith_asm(&mut self, source: &SourceLine, op: Op) -> Result<Asm, Diagnostic> {
         // match on arithmetic opertation
-        match source.arith_op {
-            ArithOp::ADD => self.asm_gen.gen_add(source),
-            ArithOp::SUB => self.asm_gen.gen_sub(source),
-            ArithOp::NEG => self.asm_gen.gen_neg(source),
-            ArithOp::EQ => self.asm_gen.gen_eq(source),
-            ArithOp::GT => self.asm_gen.gen_gt(source),
-            ArithOp::LT => self.asm_gen.gen_lt(source),
-            ArithOp::AND => self.asm_gen.gen_and(source),
-            ArithOp::OR => self.asm_gen.gen_or(source),
-            ArithOp::NOT => self.asm_gen.gen_not(source),
-            // arithmetic op unknown
-            _ => Asm::unkown(&source.source),
+        match op {
+            Op::Add => self.asm_gen.gen_add(source),
+            Op::Sub => self.asm_gen.gen_sub(source),
+            Op::Neg => self.asm_gen.gen_neg(source),
+            Op::Eq => self.asm_gen.gen_eq(source),
+            Op::Gt => self.asm_gen.gen_gt(source),
+            Op::Lt => self.asm_gen.gen_lt(source),
+            Op::And => self.asm_gen.gen_and(source),
+            Op::Or => self.asm_gen.gen_or(source),
+            Op::Not => self.asm_gen.gen_not(source),
         }
     }
 
-    fn gen_push_asm(&mut self, source: &SourceLine) -> Asm {
-        match source.mem_seg {
-            MemSeg::CONST => self
-                .asm_gen
-                .gen_push_const(source, source.args.arg2.unwrap()),
-            MemSeg::LCL => self.asm_gen.gen_push(source, "LCL"),
-            MemSeg::ARG => self.asm_gen.gen_push(source, "ARG"),
-            MemSeg::THIS => self.asm_gen.gen_push(source, "THIS"),
-            MemSeg::THAT => self.asm_gen.gen_push(source, "THAT"),
-            MemSeg::PTR => self.asm_gen.gen_push_ptr(source),
-            MemSeg::TEMP => self.asm_gen.gen_push_temp(source),
-            MemSeg::STATIC => self.asm_gen.gen_push_static(source),
-            // unknown mem seg
-            _ => Asm::unkown(&source.source),
+    fn gen_push_asm(
+        &mut self,
+        source: &SourceLine,
+        segment: Segment,
+        index: u16,
+    ) -> Result<Asm, Diagnostic> {
+        match segment {
+            Segment::Constant => self.asm_gen.gen_push_const(source, index),
+            Segment::Local => self.asm_gen.gen_push(source, "LCL", index),
+            Segment::Argument => self.asm_gen.gen_push(source, "ARG", index),
+            Segment::This => self.asm_gen.gen_push(source, "THIS", index),
+            Segment::That => self.asm_gen.gen_push(source, "THAT", index),
+            Segment::Pointer => match index {
+                0 => self.asm_gen.gen_push_ptr(source, "THIS"),
+                1 => self.asm_gen.gen_push_ptr(source, "THAT"),
+                _ => self.unknown_index(source, "pointer index 0 or 1"),
+            },
+            Segment::Temp => match index {
+                0..=7 => self.asm_gen.gen_push_temp(source, index),
+                _ => self.unknown_index(source, "temp index between 0 and 7"),
+            },
+            Segment::Static => self.asm_gen.gen_push_static(source, index),
         }
     }
 
-    pub fn gen_pop_asm(&mut self, source: &SourceLine) -> Asm {
-        match source.mem_seg {
-            MemSeg::LCL => self.asm_gen.gen_pop(source, "LCL"),
-            MemSeg::ARG => self.asm_gen.gen_pop(source, "ARG"),
-            MemSeg::THIS => self.asm_gen.gen_pop(source, "THIS"),
-            MemSeg::THAT => self.asm_gen.gen_pop(source, "THAT"),
-            MemSeg::PTR => self.asm_gen.gen_pop_ptr(source),
-            MemSeg::TEMP => self.asm_gen.gen_pop_temp(source),
-            MemSeg::STATIC => self.asm_gen.gen_pop_static(source),
-            // unknown mem seg
-            _ => Asm::unkown(&source.source),
+    pub fn gen_pop_asm(
+        &mut self,
+        source: &SourceLine,
+        segment: Segment,
+        index: u16,
+    ) -> Result<Asm, Diagnostic> {
+        match segment {
+            Segment::Local => self.asm_gen.gen_pop(source, "LCL", index),
+            Segment::Argument => self.asm_gen.gen_pop(source, "ARG", index),
+            Segment::This => self.asm_gen.gen_pop(source, "THIS", index),
+            Segment::That => self.asm_gen.gen_pop(source, "THAT", index),
+            Segment::Pointer => match index {
+                0 => self.asm_gen.gen_pop_ptr(source, "THIS"),
+                1 => self.asm_gen.gen_pop_ptr(source, "THAT"),
+                _ => self.unknown_index(source, "pointer index 0 or 1"),
+            },
+            Segment::Temp => match index {
+                0..=7 => self.asm_gen.gen_pop_temp(source, index),
+                _ => self.unknown_index(source, "temp index between 0 and 7"),
+            },
+            Segment::Static => self.asm_gen.gen_pop_static(source, index),
+            // constant is read only
+            Segment::Constant => {
+                let diagnostic = source.diagnostic(1, "a segment other than constant for pop");
+                self.unknown(source, diagnostic)
+            }
         }
     }
 }
 
+// default output of an input, Foo.vm is written to Foo.asm next to it and a
+// directory Dir to Dir/Dir.asm
+pub fn output_path(input: impl AsRef<Path>, extension: &str) -> io::Result<PathBuf> {
+    let input = input.as_ref();
+
+    if !input.is_dir() {
+        return Ok(input.with_extension(extension));
+    }
+
+    // . and .. are named after the directory they stand for
+    let name = match input.file_name() {
+        Some(name) => name.to_os_string(),
+        None => input
+            .canonicalize()?
+            .file_name()
+            .ok_or_else(|| {
+                let msg = format!("cannot name the output of {}", input.display());
+                io::Error::new(io::ErrorKind::InvalidInput, msg)
+            })?
+            .to_os_string(),
+    };
+
+    // the name may hold dots of its own, eg. Project.8
+    let mut filename = name;
+    filename.push(format!(".{extension}"));
+
+    Ok(input.join(filename))
+}
+
 pub struct CodeWriter {
-    f: File,
+    f: Box<dyn Write>,
 }
 
 impl CodeWriter {
-    pub fn new(out_fn: &str) -> Result<Self> {
-        let fn_split: Vec<&str> = out_fn.split('.').collect();
-        let filename = format!("{}.asm", fn_split.get(0).unwrap());
+    pub fn create(path: &Path) -> io::Result<Self> {
         Ok(Self {
-            f: File::create(filename)?,
+            f: Box::new(File::create(path)?),
         })
     }
 
-    pub fn write_asm(&mut self, asm: &Asm) -> Result<()> {
-        writeln!(self.f, "{}", asm.comment)?;
+    pub fn stdout() -> Self {
+        Self {
+            f: Box::new(io::stdout()),
+        }
+    }
 
-        if !asm.lines.is_empty() {
-            for line in &asm.lines {
-                writeln!(self.f, "{}", line)?;
-            }
+    pub fn write_asm(&mut self, asm: &Asm) -> io::Result<()> {
+        write!(self.f, "{asm}")
+    }
+
+    // write assembled machine words as .hack text
+    pub fn write_hack(&mut self, words: &[u16]) -> io::Result<()> {
+        write!(self.f, "{}", to_hack(words))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::Instruction;
+    use crate::parser::Parser;
+    use crate::test_dir;
+    use std::fs;
+
+    fn gen_lines(source: &str, allow_unknown: bool) -> Vec<Result<Asm, Diagnostic>> {
+        let lines = Parser::parse_source("Test.vm", source).unwrap();
+
+        let mut code = Code::new("Test", &AsmReader::new());
+        code.allow_unknown = allow_unknown;
+
+        lines.iter().map(|line| code.gen_asm(line)).collect()
+    }
+
+    #[test]
+    fn test_output_path() {
+        let dir = test_dir("output").join("Project.8");
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(output_path(&dir, "asm").unwrap(), dir.join("Project.8.asm"));
+        assert_eq!(
+            output_path(dir.join("."), "hack").unwrap(),
+            dir.join("Project.8.hack")
+        );
+        assert_eq!(
+            output_path(dir.join("Main.vm"), "asm").unwrap(),
+            dir.join("Main.asm")
+        );
+
+        assert_eq!(
+            output_path("./Prog/Main.vm", "asm").unwrap(),
+            Path::new("./Prog/Main.asm")
+        );
+        assert_eq!(
+            output_path("../Main.test.vm", "hack").unwrap(),
+            Path::new("../Main.test.hack")
+        );
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_commands_are_errors() {
+        let source = "jump LOOP now\nadd\nadd1\npush stack 1\npush pointer 2\npop constant 0\n";
+        let results = gen_lines(source, false);
+
+        let tokens: Vec<(usize, String)> = results
+            .iter()
+            .filter_map(|res| res.as_ref().err())
+            .map(|diagnostic| (diagnostic.line, diagnostic.token.clone()))
+            .collect();
+
+        assert!(results[1].is_ok());
+        assert_eq!(
+            tokens,
+            vec![
+                (1, "jump".to_string()),
+                (3, "add1".to_string()),
+                (4, "stack".to_string()),
+                (5, "2".to_string()),
+                (6, "constant".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allow_unknown() {
+        let results = gen_lines("add1\npush pointer 2\n", true);
+
+        for res in results {
+            let asm = res.unwrap();
+            assert_eq!(
+                asm.instructions,
+                vec![Instruction::Comment("UNKOWN".to_string())]
+            );
         }
-        Ok(())
+    }
+
+    #[test]
+    fn test_labels_scoped_to_function() {
+        let source = "label LOOP\nfunction Main.main 0\nlabel LOOP\ngoto LOOP\nif-goto END\n";
+        let lines = Parser::parse_source("Main.vm", source).unwrap();
+        let mut code = Code::new("Main", &AsmReader::new());
+
+        let asm: Vec<Asm> = lines
+            .iter()
+            .map(|line| code.gen_asm(line).unwrap())
+            .collect();
+
+        let lines =
+            |asm: &Asm| -> Vec<String> { asm.instructions.iter().map(|i| i.to_string()).collect() };
+
+        assert_eq!(lines(&asm[0]), vec!["(LOOP)"]);
+        assert_eq!(lines(&asm[2]), vec!["(Main.main$LOOP)"]);
+        assert_eq!(lines(&asm[3])[0], "@Main.main$LOOP");
+        assert!(lines(&asm[4]).contains(&"@Main.main$END".to_string()));
+
+        // only the label outside a function is reported
+        assert_eq!(code.warnings.len(), 1);
+        assert_eq!(code.warnings[0].line, 1);
+        assert_eq!(code.warnings[0].token, "LOOP");
     }
 }
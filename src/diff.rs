@@ -0,0 +1,446 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::asm::{Asm, AsmReader};
+use crate::code::Code;
+use crate::emulator::{Emulator, RAM_SIZE};
+use crate::error::{self, Diagnostic};
+use crate::line::Location;
+use crate::parser::Parser;
+use crate::vm::{Vm, STACK_BASE};
+
+// emulator cycles allowed for the asm of a single VM command
+const BLOCK_CYCLES: usize = 10_000;
+
+// first address past the temp segment
+const TEMP_END: usize = 13;
+
+// first address of the heap, everything from here on is compared
+const HEAP_BASE: usize = 2048;
+
+// the first VM command whose translated asm does not behave like the vm
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    // number of VM commands executed, including the divergent one
+    pub step: usize,
+    pub command: String,
+    // boxed, a location holds the tokens of its line
+    pub location: Option<Box<Location>>,
+    pub reason: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}:{}: ", location.file, location.line_num)?;
+        }
+        write!(
+            f,
+            "`{}` diverged at step {}: {}",
+            self.command, self.step, self.reason
+        )
+    }
+}
+
+// runs a program on the vm interpreter and its translated asm on the emulator in lock step
+pub struct DiffHarness {
+    vm: Vm,
+    emulator: Emulator,
+    // ROM address of the first instruction of each VM command, plus the end of the program
+    rom_start: Vec<usize>,
+    // ROM length of the bootstrap, 0 without bootstrap
+    bootstrap_len: usize,
+    started: bool,
+}
+
+impl DiffHarness {
+    // translate the (module, source) pairs in order, like Translator::source.
+    // with bootstrap, both machines start by calling Sys.init
+    pub fn new(
+        modules: &[(&str, &str)],
+        bootstrap: bool,
+        templates: &AsmReader,
+    ) -> error::Result<Self> {
+        let mut asm: Vec<Asm> = vec![];
+        let mut rom_start = vec![];
+        let mut diagnostics: Vec<Diagnostic> = vec![];
+        let mut vm = Vm::new();
+
+        // one allocator for the whole program, like translate
+        let mut code = Code::new("SysInitBootstrap", templates);
+
+        if bootstrap {
+            asm.push(code.gen_init_asm("Sys.init", STACK_BASE as u16)?);
+        }
+        let bootstrap_len = asm.iter().map(rom_len).sum();
+        let mut rom = bootstrap_len;
+
+        for (module, source) in modules {
+            let lines = Parser::parse_source(module, source)?;
+
+            code.set_filename(module);
+            for line in &lines {
+                match code.gen_asm(line) {
+                    Ok(block) => {
+                        rom_start.push(rom);
+                        rom += rom_len(&block);
+                        asm.push(block);
+                    }
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
+            }
+
+            vm.load(module, lines);
+        }
+        rom_start.push(rom);
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics.into());
+        }
+
+        let mut emulator = Emulator::load(&asm)?;
+        if !bootstrap {
+            // the bootstrap is what sets SP on the Hack platform
+            emulator.ram[0] = STACK_BASE;
+        }
+
+        Ok(Self {
+            vm,
+            emulator,
+            rom_start,
+            bootstrap_len,
+            started: false,
+        })
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    // set a RAM address on both machines, eg. LCL before running a program without bootstrap
+    pub fn set_ram(&mut self, addr: usize, value: i16) {
+        self.vm.ram[addr] = value;
+        self.emulator.ram[addr] = value;
+    }
+
+    // run at most steps VM commands, stops early once the vm halts,
+    // returns the number of commands executed
+    pub fn run(&mut self, steps: usize) -> Result<usize, Divergence> {
+        if !self.started {
+            self.start()?;
+        }
+
+        let start = self.vm.steps;
+
+        while self.vm.steps - start < steps && !self.vm.is_halted() {
+            self.step()?;
+        }
+
+        Ok(self.vm.steps - start)
+    }
+
+    fn start(&mut self) -> Result<(), Divergence> {
+        self.started = true;
+
+        if self.bootstrap_len > 0 {
+            self.vm
+                .bootstrap("Sys.init")
+                .map_err(|reason| self.divergence(None, "bootstrap", reason))?;
+
+            self.run_asm(0, self.bootstrap_len)
+                .map_err(|reason| self.divergence(None, "bootstrap", reason))?;
+        }
+
+        self.check()
+            .map_err(|reason| self.divergence(None, "bootstrap", reason))
+    }
+
+    // execute one VM command on both machines and compare them
+    fn step(&mut self) -> Result<(), Divergence> {
+        let idx = self.vm.pc;
+        let divergence = |harness: &Self, reason: String| {
+            let line = harness.vm.line(idx).unwrap();
+            harness.divergence(Some(line.location.clone()), &line.source, reason)
+        };
+
+        self.vm
+            .step()
+            .map_err(|diagnostic| divergence(self, format!("vm error, {diagnostic}")))?;
+
+        self.run_asm(self.rom_start[idx], self.rom_start[idx + 1])
+            .map_err(|reason| divergence(self, reason))?;
+
+        self.check().map_err(|reason| divergence(self, reason))
+    }
+
+    // run the asm of one command, from its first instruction until it leaves start..end
+    fn run_asm(&mut self, start: usize, end: usize) -> Result<(), String> {
+        if start == end {
+            return Ok(());
+        }
+
+        let mut cycles = 0;
+        loop {
+            self.emulator.step().map_err(|err| err.to_string())?;
+            cycles += 1;
+
+            if self.emulator.pc <= start || self.emulator.pc >= end {
+                return Ok(());
+            }
+            if cycles == BLOCK_CYCLES {
+                return Err(format!("asm did not finish within {BLOCK_CYCLES} cycles"));
+            }
+        }
+    }
+
+    // compare control flow, registers, segments, statics, stack and heap
+    fn check(&self) -> Result<(), String> {
+        let expected_pc = self.rom_addr(self.vm.pc as i16);
+        if self.emulator.pc != expected_pc {
+            return Err(format!(
+                "asm continued at ROM[{}], expected ROM[{}]",
+                self.emulator.pc, expected_pc
+            ));
+        }
+
+        let vm_ram = &self.vm.ram;
+        let asm_ram = &self.emulator.ram;
+
+        // SP, LCL, ARG, THIS, THAT and temp, R13-R15 are asm scratch registers
+        for addr in 0..TEMP_END {
+            self.check_addr(addr, vm_ram[addr], asm_ram[addr])?;
+        }
+
+        for (symbol, addr) in self.vm.statics() {
+            let asm_addr = self
+                .emulator
+                .symbol(symbol)
+                .ok_or_else(|| format!("static {symbol} is missing from the asm"))?;
+
+            let (vm_value, asm_value) = (vm_ram[addr as usize], asm_ram[asm_addr as usize]);
+            if vm_value != asm_value {
+                return Err(format!(
+                    "static {symbol} is {vm_value} in the vm, {asm_value} in the asm"
+                ));
+            }
+        }
+
+        let sp = (vm_ram[0].max(STACK_BASE) as usize).min(HEAP_BASE);
+        let return_slots = self.vm.return_slots();
+        for addr in STACK_BASE as usize..sp {
+            let expected = if return_slots.contains(&addr) {
+                self.rom_addr(vm_ram[addr]) as i16
+            } else {
+                vm_ram[addr]
+            };
+            self.check_addr(addr, expected, asm_ram[addr])?;
+        }
+
+        if vm_ram[HEAP_BASE..] != asm_ram[HEAP_BASE..] {
+            for addr in HEAP_BASE..RAM_SIZE {
+                self.check_addr(addr, vm_ram[addr], asm_ram[addr])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_addr(&self, addr: usize, expected: i16, actual: i16) -> Result<(), String> {
+        if expected == actual {
+            return Ok(());
+        }
+
+        let name = match addr {
+            0 => "SP".to_string(),
+            1 => "LCL".to_string(),
+            2 => "ARG".to_string(),
+            3 => "THIS".to_string(),
+            4 => "THAT".to_string(),
+            5..=12 => format!("temp {}", addr - 5),
+            _ => format!("RAM[{addr}]"),
+        };
+        Err(format!(
+            "{name} is {expected} in the vm, {actual} in the asm"
+        ))
+    }
+
+    // ROM address matching a vm command index, returning past the last command
+    // lands right after the bootstrap as the asm bootstrap's return label does
+    fn rom_addr(&self, idx: i16) -> usize {
+        match self.rom_start.get(idx as usize) {
+            Some(&addr) if (idx as usize) < self.vm.len() => addr,
+            _ if self.bootstrap_len > 0 => self.bootstrap_len,
+            Some(&addr) => addr,
+            None => usize::MAX,
+        }
+    }
+
+    fn divergence(&self, location: Option<Location>, command: &str, reason: String) -> Divergence {
+        Divergence {
+            step: self.vm.steps,
+            command: command.to_string(),
+            location: location.map(Box::new),
+            reason,
+        }
+    }
+}
+
+// number of ROM words of an asm block, labels and comments take no space
+fn rom_len(asm: &Asm) -> usize {
+    asm.instructions
+        .iter()
+        .filter(|instruction| instruction.is_code())
+        .count()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn harness(modules: &[(&str, &str)], bootstrap: bool) -> DiffHarness {
+        DiffHarness::new(modules, bootstrap, &AsmReader::new()).unwrap()
+    }
+
+    fn assert_same(harness: &mut DiffHarness) {
+        if let Err(divergence) = harness.run(100_000) {
+            panic!("{divergence}");
+        }
+        assert!(harness.vm().is_halted());
+    }
+
+    #[test]
+    fn test_stack_arithmetic() {
+        let source = "push constant 17\npush constant 17\neq\npush constant 892\npush constant 891\nlt\n\
+                      push constant 32767\npush constant 32766\ngt\npush constant 57\npush constant 31\n\
+                      push constant 53\nadd\npush constant 112\nsub\nneg\nand\npush constant 82\nor\nnot\n";
+        let mut harness = harness(&[("StackTest", source)], false);
+
+        assert_same(&mut harness);
+        assert_eq!(harness.vm().ram[0], 260);
+        assert_eq!(&harness.vm().ram[256..260], &[-1, 0, -1, -91]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let source = "push constant 10\npop local 0\npush constant 21\npush constant 22\n\
+                      pop argument 2\npop argument 1\npush constant 36\npop this 6\n\
+                      push constant 42\npush constant 45\npop that 5\npop that 2\n\
+                      push constant 510\npop temp 6\npush local 0\npush that 5\nadd\n\
+                      push argument 1\nsub\npush this 6\npush this 6\nadd\nsub\npush temp 6\nadd\n\
+                      push constant 3030\npop pointer 0\npush constant 3040\npop pointer 1\n\
+                      push pointer 0\npush pointer 1\nadd\n";
+        let mut harness = harness(&[("BasicTest", source)], false);
+        harness.set_ram(1, 300);
+        harness.set_ram(2, 400);
+        harness.set_ram(3, 3000);
+        harness.set_ram(4, 3010);
+
+        assert_same(&mut harness);
+        assert_eq!(harness.vm().ram[256], 472);
+        assert_eq!(harness.vm().ram[257], 6070);
+    }
+
+    #[test]
+    fn test_statics_across_files() {
+        let class1 = "function Class1.set 0\npush argument 0\npop static 0\npush argument 1\n\
+                      pop static 1\npush constant 0\nreturn\n\
+                      function Class1.get 1\npush argument 0\npop static 2\npush static 0\n\
+                      push static 1\nsub\nreturn\n";
+        let class2 = "function Class2.set 0\npush argument 0\npop static 0\npush argument 1\n\
+                      pop static 1\npush constant 0\nreturn\n\
+                      function Class2.get 1\npush argument 0\npop static 2\npush static 0\n\
+                      push static 1\nsub\nreturn\n";
+        let sys = "function Sys.init 0\npush constant 6\npush constant 8\ncall Class1.set 2\n\
+                   pop temp 0\npush constant 23\npush constant 15\ncall Class2.set 2\npop temp 0\n\
+                   push constant 1\ncall Class1.get 1\npush constant 1\ncall Class2.get 1\n\
+                   label WHILE\ngoto WHILE\n";
+        let files = [("Sys", sys), ("Class1", class1), ("Class2", class2)];
+        let mut harness = harness(&files, true);
+
+        assert_same(&mut harness);
+        assert_eq!(&harness.vm().ram[261..263], &[-2, 8]);
+        assert_eq!(harness.emulator().symbol("Class2.0"), Some(19));
+    }
+
+    #[test]
+    fn test_comparisons_across_files() {
+        // both files emit comparison labels, they must not share a definition
+        let other = "function Other.less 0\npush argument 0\npush constant 2\nlt\nreturn\n";
+        let sys = "function Sys.init 0\npush constant 3\npush constant 3\neq\n\
+                   push constant 5\ncall Other.less 1\nlabel WHILE\ngoto WHILE\n";
+        let mut harness = harness(&[("Sys", sys), ("Other", other)], true);
+
+        assert_same(&mut harness);
+        assert_eq!(&harness.vm().ram[261..263], &[-1, 0]);
+    }
+
+    #[test]
+    fn test_zero_argument_calls() {
+        // ARG[0] of a zero argument call is the saved return address slot
+        let main = "function Main.zero 1\npush constant 40\npop local 0\npush local 0\n\
+                    push constant 2\nadd\ncall Main.inner 0\nadd\nreturn\n\
+                    function Main.inner 0\npush constant 100\nreturn\n";
+        let sys = "function Sys.init 0\npush constant 1\ncall Main.zero 0\npush constant 2\n\
+                   add\nlabel WHILE\ngoto WHILE\n";
+        let mut harness = harness(&[("Sys", sys), ("Main", main)], true);
+
+        assert_same(&mut harness);
+        assert_eq!(harness.vm().ram[0], 263);
+        assert_eq!(&harness.vm().ram[261..263], &[1, 144]);
+    }
+
+    #[test]
+    fn test_loops_outside_functions() {
+        // fibonacci series of argument 0 numbers written from argument 1 on
+        let source =
+            "push argument 1\npop pointer 1\npush constant 0\npop that 0\npush constant 1\n\
+                      pop that 1\npush argument 0\npush constant 2\nsub\npop argument 0\n\
+                      label MAIN_LOOP_START\npush argument 0\nif-goto COMPUTE_ELEMENT\n\
+                      goto END_PROGRAM\nlabel COMPUTE_ELEMENT\npush that 0\npush that 1\nadd\n\
+                      pop that 2\npush pointer 1\npush constant 1\nadd\npop pointer 1\n\
+                      push argument 0\npush constant 1\nsub\npop argument 0\ngoto MAIN_LOOP_START\n\
+                      label END_PROGRAM\n";
+        let mut harness = harness(&[("FibonacciSeries", source)], false);
+        harness.set_ram(2, 400);
+        harness.set_ram(400, 8);
+        harness.set_ram(401, 3000);
+
+        assert_same(&mut harness);
+        assert_eq!(&harness.vm().ram[3000..3008], &[0, 1, 1, 2, 3, 5, 8, 13]);
+    }
+
+    #[test]
+    fn test_recursive_calls() {
+        let main = "function Main.fibonacci 0\npush argument 0\npush constant 2\nlt\n\
+                    if-goto IF_TRUE\ngoto IF_FALSE\nlabel IF_TRUE\npush argument 0\nreturn\n\
+                    label IF_FALSE\npush argument 0\npush constant 2\nsub\ncall Main.fibonacci 1\n\
+                    push argument 0\npush constant 1\nsub\ncall Main.fibonacci 1\nadd\nreturn\n";
+        let sys = "function Sys.init 0\npush constant 6\ncall Main.fibonacci 1\n\
+                   label WHILE\ngoto WHILE\n";
+        let mut harness = harness(&[("Sys", sys), ("Main", main)], true);
+
+        assert_same(&mut harness);
+        assert_eq!(harness.vm().ram[0], 262);
+        assert_eq!(harness.vm().ram[261], 8);
+    }
+
+    #[test]
+    fn test_reports_first_divergent_command() {
+        let modules = [("Broken", "push constant 7\npush constant 8\nadd\n")];
+
+        // push constant pushes one more than asked
+        let mut templates = AsmReader::new();
+        templates.push_const[1] = "D=A+1".to_string();
+
+        let mut harness = DiffHarness::new(&modules, false, &templates).unwrap();
+        let divergence = harness.run(100).unwrap_err();
+
+        assert_eq!(divergence.step, 1);
+        assert_eq!(divergence.command, "push constant 7");
+        assert_eq!(divergence.location.unwrap().line_num, 1);
+        assert_eq!(divergence.reason, "RAM[256] is 7 in the vm, 8 in the asm");
+    }
+}
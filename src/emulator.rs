@@ -0,0 +1,345 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::asm::Asm;
+use crate::assembler::{AsmError, Assembler, SymbolTable};
+
+pub const RAM_SIZE: usize = 32768;
+
+// C-instruction fields
+const C_INSTRUCTION: u16 = 0b1000_0000_0000_0000;
+const JMP: u16 = 0b111;
+
+// an instruction reading or writing M at an address past the end of RAM,
+// eg. with a negative A
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmulatorError {
+    pub pc: usize,
+    pub addr: usize,
+}
+
+impl Display for EmulatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "instruction {} accesses RAM[{}], past the end of RAM",
+            self.pc, self.addr
+        )
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+pub struct Emulator {
+    rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+    // total number of instructions executed
+    pub cycles: usize,
+    symbols: SymbolTable,
+}
+
+impl Emulator {
+    // load assembled Hack machine words
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self::with_symbols(rom, SymbolTable::new())
+    }
+
+    // assemble and load the asm generated by gen_asm
+    pub fn load(asm: &[Asm]) -> Result<Self, AsmError> {
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble(asm)?;
+
+        Ok(Self::with_symbols(rom, assembler.symbols))
+    }
+
+    pub fn load_lines(lines: &[&str]) -> Result<Self, AsmError> {
+        let mut assembler = Assembler::new();
+        let rom = assembler.assemble_lines(lines)?;
+
+        Ok(Self::with_symbols(rom, assembler.symbols))
+    }
+
+    fn with_symbols(rom: Vec<u16>, symbols: SymbolTable) -> Self {
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+            symbols,
+        }
+    }
+
+    // address of a label, predefined symbol or variable
+    pub fn symbol(&self, symbol: &str) -> Option<u16> {
+        self.symbols.get(symbol)
+    }
+
+    pub fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
+
+    // halted once the pc leaves the program or sits on an @self 0;JMP loop
+    // that changes no register
+    pub fn is_halted(&self) -> bool {
+        match (self.rom.get(self.pc), self.rom.get(self.pc + 1)) {
+            (None, _) => true,
+            (Some(addr), Some(ins)) if addr & C_INSTRUCTION == 0 && ins & C_INSTRUCTION != 0 => {
+                // no dest bits, unconditional jump
+                *addr as usize == self.pc && ins & 0b111_111 == JMP
+            }
+            _ => false,
+        }
+    }
+
+    // execute a single instruction, returns false if already halted, an
+    // instruction using M past the end of RAM is an error and is not executed
+    pub fn step(&mut self) -> Result<bool, EmulatorError> {
+        let ins = match self.rom.get(self.pc) {
+            Some(ins) => *ins,
+            None => return Ok(false),
+        };
+
+        if ins & C_INSTRUCTION == 0 {
+            self.a = ins as i16;
+            self.pc += 1;
+        } else {
+            let comp = (ins >> 6) & 0b111_1111;
+            let dest = (ins >> 3) & 0b111;
+            let jump = ins & 0b111;
+
+            let addr = self.a as u16 as usize;
+
+            let uses_m = comp & 0b100_0000 != 0 || dest & 0b001 != 0;
+            if uses_m && addr >= self.ram.len() {
+                return Err(EmulatorError { pc: self.pc, addr });
+            }
+
+            let y = if comp & 0b100_0000 != 0 {
+                self.ram[addr]
+            } else {
+                self.a
+            };
+            let out = alu(self.d, y, comp);
+
+            // M is written using the address held in A before this instruction
+            if dest & 0b001 != 0 {
+                self.ram[addr] = out;
+            }
+            if dest & 0b010 != 0 {
+                self.d = out;
+            }
+            if dest & 0b100 != 0 {
+                self.a = out;
+            }
+
+            let jumped = (jump & 0b100 != 0 && out < 0)
+                || (jump & 0b010 != 0 && out == 0)
+                || (jump & 0b001 != 0 && out > 0);
+
+            self.pc = if jumped {
+                self.a as u16 as usize
+            } else {
+                self.pc + 1
+            };
+        }
+        self.cycles += 1;
+
+        Ok(true)
+    }
+
+    // run for at most cycles instructions, stops early once halted,
+    // returns the number of instructions executed
+    pub fn run(&mut self, cycles: usize) -> Result<usize, EmulatorError> {
+        let start = self.cycles;
+
+        while self.cycles - start < cycles && !self.is_halted() {
+            self.step()?;
+        }
+
+        Ok(self.cycles - start)
+    }
+}
+
+// Hack ALU, the low 6 bits of comp are zx nx zy ny f no
+fn alu(x: i16, y: i16, comp: u16) -> i16 {
+    let bit = |n: u16| comp & (1 << n) != 0;
+
+    let mut x = if bit(5) { 0 } else { x };
+    if bit(4) {
+        x = !x;
+    }
+    let mut y = if bit(3) { 0 } else { y };
+    if bit(2) {
+        y = !y;
+    }
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::translator::Translator;
+
+    fn run_lines(lines: &[&str], cycles: usize) -> Emulator {
+        let mut emulator = Emulator::load_lines(lines).unwrap();
+        emulator.run(cycles).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_comp_mnemonics() {
+        // D=6, A=3 stored in M at address 3 as 10
+        let cases = [
+            ("0", 0),
+            ("1", 1),
+            ("-1", -1),
+            ("D", 6),
+            ("A", 3),
+            ("!D", !6),
+            ("!A", !3),
+            ("-D", -6),
+            ("-A", -3),
+            ("D+1", 7),
+            ("A+1", 4),
+            ("D-1", 5),
+            ("A-1", 2),
+            ("D+A", 9),
+            ("D-A", 3),
+            ("A-D", -3),
+            ("D&A", 2),
+            ("D|A", 7),
+            ("M", 10),
+            ("!M", !10),
+            ("-M", -10),
+            ("M+1", 11),
+            ("M-1", 9),
+            ("D+M", 16),
+            ("D-M", -4),
+            ("M-D", 4),
+            ("D&M", 2),
+            ("D|M", 14),
+            ("M+D", 16),
+        ];
+
+        for (comp, expected) in cases {
+            let set_d = format!("D={comp}");
+            let lines = ["@10", "D=A", "@3", "M=D", "@6", "D=A", "@3", set_d.as_str()];
+
+            let emulator = run_lines(&lines, 100);
+            assert_eq!(emulator.d, expected, "comp {comp}");
+        }
+    }
+
+    #[test]
+    fn test_dest_and_jumps() {
+        // AMD written from the same out, M uses the old A
+        let emulator = run_lines(&["@20", "AMD=A+1"], 10);
+        assert_eq!((emulator.a, emulator.d, emulator.ram[20]), (21, 21, 21));
+
+        for (jump, value, taken) in [
+            ("JGT", 1, true),
+            ("JGT", 0, false),
+            ("JEQ", 0, true),
+            ("JGE", 0, true),
+            ("JLT", -1, true),
+            ("JLT", 0, false),
+            ("JNE", -1, true),
+            ("JNE", 0, false),
+            ("JLE", 1, false),
+            ("JMP", 0, true),
+        ] {
+            let set_d = format!("@{}", value.max(0));
+            let neg = if value < 0 { "D=-1" } else { "D=A" };
+            let jump = format!("D;{jump}");
+            let lines = [
+                set_d.as_str(),
+                neg,
+                "@SKIP",
+                jump.as_str(),
+                "@R1",
+                "M=1",
+                "(SKIP)",
+            ];
+
+            let emulator = run_lines(&lines, 100);
+            assert_eq!(emulator.ram[1] == 0, taken, "{jump} with {value}");
+        }
+    }
+
+    #[test]
+    fn test_symbols() {
+        let lines = [
+            "@SP", "D=A", "@THAT", "D=D+A", "@R13", "D=D+A", "@KBD", "D=A", "@Foo.1", "M=1",
+            "@Foo.2", "M=1", "@Foo.1", "M=M+1", "(END)", "@END", "0;JMP",
+        ];
+        let emulator = run_lines(&lines, 1000);
+
+        assert_eq!(emulator.symbol("SCREEN"), Some(16384));
+        assert_eq!(emulator.d, 24576);
+
+        // variables are allocated from 16 in order of first use
+        assert_eq!(emulator.symbol("Foo.1"), Some(16));
+        assert_eq!(emulator.symbol("Foo.2"), Some(17));
+        assert_eq!(emulator.ram[16], 2);
+        assert_eq!(emulator.symbol("END"), Some(14));
+    }
+
+    #[test]
+    fn test_halt_loop() {
+        let mut emulator = Emulator::load_lines(&["@3", "D=A", "(END)", "@END", "0;JMP"]).unwrap();
+
+        assert_eq!(emulator.run(1000), Ok(2));
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.pc, 2);
+
+        // no halt loop, stops after the requested cycles
+        let mut emulator = Emulator::load_lines(&["(LOOP)", "@LOOP", "D=D+1;JMP"]).unwrap();
+        assert_eq!(emulator.run(50), Ok(50));
+        assert!(!emulator.is_halted());
+    }
+
+    #[test]
+    fn test_address_past_ram() {
+        // A=-1 is address 65535
+        let mut emulator = Emulator::load_lines(&["A=-1", "D=A", "M=D"]).unwrap();
+
+        let err = emulator.run(10).unwrap_err();
+        assert_eq!(err, EmulatorError { pc: 2, addr: 65535 });
+        // the instruction is not executed
+        assert_eq!((emulator.pc, emulator.cycles), (2, 2));
+
+        // A alone may hold any value
+        let mut emulator = Emulator::load_lines(&["A=-1", "D=A+1"]).unwrap();
+        assert_eq!(emulator.run(10), Ok(2));
+    }
+
+    #[test]
+    fn test_run_generated_asm() {
+        let asm = Translator::new()
+            .source(
+                "Test",
+                "push constant 7\npush constant 8\nadd\npush constant 3\nlt\n",
+            )
+            .translate()
+            .unwrap()
+            .asm;
+
+        let mut emulator = Emulator::load(&asm).unwrap();
+        emulator.ram[0] = 256;
+        emulator.run(1000).unwrap();
+
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.ram[0], 257);
+        // 15 < 3 is false
+        assert_eq!(emulator.ram[256], 0);
+    }
+}
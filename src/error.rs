@@ -0,0 +1,134 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use crate::assembler::AsmError;
+use crate::emulator::EmulatorError;
+use crate::template::TemplateError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // reported, translation still succeeds
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// a problem found in a .vm source file, points at the offending token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub token: String,
+    pub expected: String,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    pub fn new(file: &str, line: usize, column: usize, token: &str, expected: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            line,
+            column,
+            token: token.to_string(),
+            expected: expected.to_string(),
+            severity: Severity::Error,
+        }
+    }
+
+    pub fn into_warning(self) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..self
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: ",
+            self.file, self.line, self.column, self.severity
+        )?;
+
+        if self.token.is_empty() {
+            write!(f, "expected {}, found end of line", self.expected)
+        } else {
+            write!(f, "expected {}, found `{}`", self.expected, self.token)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // every diagnostic found, not only the first one
+    Diagnostics(Vec<Diagnostic>),
+    Asm(AsmError),
+    Template(TemplateError),
+    // a program run on the CPU emulator went past the end of RAM
+    Emulator(EmulatorError),
+    // options that do not fit the program, eg. an entry function it does not define
+    Usage(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "error: {err}"),
+            Error::Diagnostics(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{diagnostic}")?;
+                }
+                write!(f, "{} error(s) found", diagnostics.len())
+            }
+            Error::Asm(err) => write!(f, "error: {err}"),
+            Error::Template(err) => write!(f, "error: {err}"),
+            Error::Emulator(err) => write!(f, "error: {err}"),
+            Error::Usage(msg) => write!(f, "error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<AsmError> for Error {
+    fn from(err: AsmError) -> Self {
+        Error::Asm(err)
+    }
+}
+
+impl From<TemplateError> for Error {
+    fn from(err: TemplateError) -> Self {
+        Error::Template(err)
+    }
+}
+
+impl From<EmulatorError> for Error {
+    fn from(err: EmulatorError) -> Self {
+        Error::Emulator(err)
+    }
+}
+
+impl From<Vec<Diagnostic>> for Error {
+    fn from(diagnostics: Vec<Diagnostic>) -> Self {
+        Error::Diagnostics(diagnostics)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
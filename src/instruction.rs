@@ -0,0 +1,367 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::assembler::AsmError;
+
+// comp mnemonics to the a bit followed by c1..c6, the first mnemonic of
+// each encoding is the one written out
+const COMPS: [(&str, u8); 34] = [
+    ("0", 0b0101010),
+    ("1", 0b0111111),
+    ("-1", 0b0111010),
+    ("D", 0b0001100),
+    ("A", 0b0110000),
+    ("!D", 0b0001101),
+    ("!A", 0b0110001),
+    ("-D", 0b0001111),
+    ("-A", 0b0110011),
+    ("D+1", 0b0011111),
+    ("A+1", 0b0110111),
+    ("D-1", 0b0001110),
+    ("A-1", 0b0110010),
+    ("D+A", 0b0000010),
+    ("A+D", 0b0000010),
+    ("D-A", 0b0010011),
+    ("A-D", 0b0000111),
+    ("D&A", 0b0000000),
+    ("A&D", 0b0000000),
+    ("D|A", 0b0010101),
+    ("A|D", 0b0010101),
+    ("M", 0b1110000),
+    ("!M", 0b1110001),
+    ("-M", 0b1110011),
+    ("M+1", 0b1110111),
+    ("M-1", 0b1110010),
+    ("D+M", 0b1000010),
+    ("M+D", 0b1000010),
+    ("D-M", 0b1010011),
+    ("M-D", 0b1000111),
+    ("D&M", 0b1000000),
+    ("M&D", 0b1000000),
+    ("D|M", 0b1010101),
+    ("M|D", 0b1010101),
+];
+
+const JUMPS: [(&str, u8); 7] = [
+    ("JGT", 0b001),
+    ("JEQ", 0b010),
+    ("JGE", 0b011),
+    ("JLT", 0b100),
+    ("JNE", 0b101),
+    ("JLE", 0b110),
+    ("JMP", 0b111),
+];
+
+// registers written by a C-instruction, bits are A D M
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dest(u8);
+
+impl Dest {
+    pub const NONE: Dest = Dest(0);
+
+    fn parse(dest: &str) -> Option<Self> {
+        let mut bits = 0;
+        for reg in dest.chars() {
+            let bit = match reg {
+                'A' => 0b100,
+                'D' => 0b010,
+                'M' => 0b001,
+                _ => return None,
+            };
+            // each register only once
+            if bits & bit != 0 {
+                return None;
+            }
+            bits |= bit;
+        }
+        Some(Dest(bits))
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl Display for Dest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (bit, reg) in [(0b100, 'A'), (0b001, 'M'), (0b010, 'D')] {
+            if self.0 & bit != 0 {
+                write!(f, "{reg}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ALU computation, the a bit followed by c1..c6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comp(u8);
+
+impl Comp {
+    fn parse(comp: &str) -> Option<Self> {
+        COMPS
+            .iter()
+            .find(|(mnemonic, _)| *mnemonic == comp)
+            .map(|(_, bits)| Comp(*bits))
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl Display for Comp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (mnemonic, _) = COMPS
+            .iter()
+            .find(|(_, bits)| *bits == self.0)
+            .expect("comp is built from the table");
+        write!(f, "{mnemonic}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Jump(u8);
+
+impl Jump {
+    pub const NONE: Jump = Jump(0);
+
+    fn parse(jump: &str) -> Option<Self> {
+        JUMPS
+            .iter()
+            .find(|(mnemonic, _)| *mnemonic == jump)
+            .map(|(_, bits)| Jump(*bits))
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0 as u16
+    }
+}
+
+impl Display for Jump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match JUMPS.iter().find(|(_, bits)| *bits == self.0) {
+            Some((mnemonic, _)) => write!(f, "{mnemonic}"),
+            None => Ok(()),
+        }
+    }
+}
+
+// the operand of an A-instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Literal(u16),
+    Symbol(String),
+}
+
+impl Value {
+    // a symbol checked to be one the assembler reads as a symbol, eg. 1 is an address
+    pub fn symbol(symbol: &str) -> Result<Self, AsmError> {
+        if is_symbol(symbol) {
+            Ok(Value::Symbol(symbol.to_string()))
+        } else {
+            Err(AsmError::new(&format!("@{symbol}"), "a constant or symbol"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    // @value
+    A(Value),
+    // dest=comp;jump, dest and jump are optional
+    C { dest: Dest, comp: Comp, jump: Jump },
+    // (LABEL)
+    Label(String),
+    // //comment, takes no ROM
+    Comment(String),
+}
+
+impl Instruction {
+    // (label), checked to be a valid symbol
+    pub fn label(label: &str) -> Result<Self, AsmError> {
+        if is_symbol(label) {
+            Ok(Instruction::Label(label.to_string()))
+        } else {
+            Err(AsmError::new(
+                &format!("({label})"),
+                "a label of the form (LABEL)",
+            ))
+        }
+    }
+
+    // a single instruction without a trailing comment
+    pub fn parse(line: &str) -> Result<Self, AsmError> {
+        let line = line.trim();
+
+        if let Some(comment) = line.strip_prefix("//") {
+            return Ok(Instruction::Comment(comment.to_string()));
+        }
+
+        if let Some(label) = line.strip_prefix('(') {
+            return label
+                .strip_suffix(')')
+                .filter(|label| is_symbol(label))
+                .map(|label| Instruction::Label(label.to_string()))
+                .ok_or_else(|| AsmError::new(line, "a label of the form (LABEL)"));
+        }
+
+        if let Some(value) = line.strip_prefix('@') {
+            if value.starts_with(|c: char| c.is_ascii_digit()) {
+                return match value.parse::<u16>() {
+                    Ok(value) if value <= i16::MAX as u16 => {
+                        Ok(Instruction::A(Value::Literal(value)))
+                    }
+                    _ => Err(AsmError::new(line, "a constant between 0 and 32767")),
+                };
+            }
+            if !is_symbol(value) {
+                return Err(AsmError::new(line, "a constant or symbol"));
+            }
+            return Ok(Instruction::A(Value::Symbol(value.to_string())));
+        }
+
+        Self::parse_c(line)
+    }
+
+    fn parse_c(line: &str) -> Result<Self, AsmError> {
+        let (dest, rest) = match line.split_once('=') {
+            Some((dest, rest)) => (Some(dest), rest),
+            None => (None, line),
+        };
+        let (comp, jump) = match rest.split_once(';') {
+            Some((comp, jump)) => (comp, Some(jump)),
+            None => (rest, None),
+        };
+
+        let dest = match dest {
+            Some(dest) => {
+                Dest::parse(dest).ok_or_else(|| AsmError::new(line, "a dest of A, D and M"))?
+            }
+            None => Dest::NONE,
+        };
+        let comp = Comp::parse(comp).ok_or_else(|| AsmError::new(line, "a valid comp mnemonic"))?;
+        let jump = match jump {
+            Some(jump) => {
+                Jump::parse(jump).ok_or_else(|| AsmError::new(line, "a jump mnemonic"))?
+            }
+            None => Jump::NONE,
+        };
+
+        Ok(Instruction::C { dest, comp, jump })
+    }
+
+    // A and C-instructions take a word of ROM, labels and comments do not
+    pub fn is_code(&self) -> bool {
+        matches!(self, Instruction::A(_) | Instruction::C { .. })
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::A(Value::Literal(value)) => write!(f, "@{value}"),
+            Instruction::A(Value::Symbol(symbol)) => write!(f, "@{symbol}"),
+            Instruction::C { dest, comp, jump } => {
+                if *dest != Dest::NONE {
+                    write!(f, "{dest}=")?;
+                }
+                write!(f, "{comp}")?;
+                if *jump != Jump::NONE {
+                    write!(f, ";{jump}")?;
+                }
+                Ok(())
+            }
+            Instruction::Label(label) => write!(f, "({label})"),
+            Instruction::Comment(comment) => write!(f, "//{comment}"),
+        }
+    }
+}
+
+// letters, digits, _ . $ and :, not starting with a digit
+fn is_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && !symbol.starts_with(|c: char| c.is_ascii_digit())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_instructions() {
+        assert_eq!(
+            Instruction::parse("@21").unwrap(),
+            Instruction::A(Value::Literal(21))
+        );
+        assert_eq!(
+            Instruction::parse(" @Main.main$ret.1 ").unwrap(),
+            Instruction::A(Value::Symbol("Main.main$ret.1".to_string()))
+        );
+        assert_eq!(
+            Instruction::parse("(TRUE_1)").unwrap(),
+            Instruction::Label("TRUE_1".to_string())
+        );
+        assert!(!Instruction::parse("//UNKOWN").unwrap().is_code());
+
+        let Instruction::C { dest, comp, jump } = Instruction::parse("AM=M-1").unwrap() else {
+            panic!("expected a C-instruction");
+        };
+        assert_eq!(
+            (dest.bits(), comp.bits(), jump.bits()),
+            (0b101, 0b1110010, 0)
+        );
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for line in [
+            "@0",
+            "@SP",
+            "(END)",
+            "D=A",
+            "AM=M-1",
+            "AMD=D|M",
+            "MD=M+1",
+            "0;JMP",
+            "D;JNE",
+            "D=D+A;JGT",
+            "//push constant 7",
+        ] {
+            assert_eq!(Instruction::parse(line).unwrap().to_string(), line);
+        }
+
+        // commutative forms and dest order are written the canonical way
+        assert_eq!(Instruction::parse("DM=M+D").unwrap().to_string(), "MD=D+M");
+    }
+
+    #[test]
+    fn test_checked_symbols() {
+        assert_eq!(
+            Value::symbol("Main.main$END").unwrap(),
+            Value::Symbol("Main.main$END".to_string())
+        );
+        assert!(Value::symbol("1").is_err());
+        assert!(Value::symbol("a-b").is_err());
+        assert!(Instruction::label("LOOP").is_ok());
+        assert!(Instruction::label("").is_err());
+    }
+
+    #[test]
+    fn test_invalid_instructions() {
+        for (line, expected) in [
+            ("M=–D", "a valid comp mnemonic"),
+            ("X=D", "a dest of A, D and M"),
+            ("DD=1", "a dest of A, D and M"),
+            ("D;JXX", "a jump mnemonic"),
+            ("@40000", "a constant between 0 and 32767"),
+            ("@a b", "a constant or symbol"),
+            ("(OPEN", "a label of the form (LABEL)"),
+        ] {
+            let err = Instruction::parse(line).unwrap_err();
+            assert_eq!(err.expected, expected, "{line}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// 1 based position of a token within its file, len counts chars
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    // column just past the token
+    pub fn end(&self) -> usize {
+        self.column + self.len
+    }
+}
+
+// a word of a source line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub span: Span,
+}
+
+// split a line into words on any whitespace, including tabs and the \r of
+// CRLF line endings, everything after // is a comment
+pub fn tokenize(line: usize, raw: &str) -> Vec<Token> {
+    let code = match raw.find("//") {
+        Some(idx) => &raw[..idx],
+        None => raw,
+    };
+
+    let mut tokens = vec![];
+    // byte offset and column of the word being read
+    let mut start: Option<(usize, usize)> = None;
+
+    for (column, (idx, c)) in code.char_indices().enumerate() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((idx, column + 1)),
+            (true, Some((word_idx, word_column))) => {
+                tokens.push(token(line, &code[word_idx..idx], word_column));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((word_idx, word_column)) = start {
+        tokens.push(token(line, &code[word_idx..], word_column));
+    }
+
+    tokens
+}
+
+fn token(line: usize, text: &str, column: usize) -> Token {
+    Token {
+        text: text.to_string(),
+        span: Span {
+            line,
+            column,
+            len: text.chars().count(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn words(raw: &str) -> Vec<(String, usize)> {
+        tokenize(1, raw)
+            .into_iter()
+            .map(|token| (token.text, token.span.column))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_whitespace() {
+        assert_eq!(
+            words("\tpush  constant\t7\r"),
+            vec![
+                ("push".to_string(), 2),
+                ("constant".to_string(), 8),
+                ("7".to_string(), 17)
+            ]
+        );
+        assert!(words(" \t\r").is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_comments() {
+        assert!(words("    // indented comment").is_empty());
+        assert_eq!(
+            words("add// no space before the comment"),
+            vec![("add".to_string(), 1)]
+        );
+
+        let tokens = tokenize(3, "  goto LOOP // back");
+        assert_eq!(
+            tokens[1].span,
+            Span {
+                line: 3,
+                column: 8,
+                len: 4
+            }
+        );
+        assert_eq!(tokens[1].span.end(), 12);
+    }
+}
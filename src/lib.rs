@@ -0,0 +1,35 @@
+// translates nand2tetris VM code to Hack assembly, see Translator for the
+// entry point used by the hvm-translator binary
+
+pub mod asm;
+pub mod assembler;
+pub mod code;
+// differential tests of the translator against the vm interpreter
+#[cfg(test)]
+mod diff;
+pub mod emulator;
+pub mod error;
+pub mod instruction;
+pub mod lexer;
+pub mod line;
+pub mod parser;
+pub mod template;
+pub mod translator;
+// runs CPU emulator .tst scripts against translated programs
+pub mod tst;
+// reference semantics of VM programs
+pub mod vm;
+
+pub use asm::{Asm, AsmReader};
+pub use error::{Diagnostic, Error, Result};
+pub use translator::{Translation, Translator};
+
+// a fresh directory of a test, unique to the process and removed first so
+// files left by an earlier run do not change the result, the test removes it
+#[cfg(test)]
+fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("hvm_translator_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
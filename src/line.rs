@@ -1,77 +1,156 @@
 use std::fmt::{self, Display, Formatter};
 
-#[derive(Debug)]
-pub struct Args {
-    pub arg1: String,
-    pub arg2: Option<i32>,
+use crate::error::Diagnostic;
+use crate::lexer::{tokenize, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Local,
+    Argument,
+    This,
+    That,
+    Constant,
+    Static,
+    Temp,
+    Pointer,
 }
 
-#[derive(Debug)]
-pub enum CommandType {
-    ARITHMETIC,
-    PUSH,
-    POP,
-    LABEL,
-    GOTO,
-    IF,
-    FUNCTION,
-    RETURN,
-    CALL,
-    UNKOWN,
+impl Segment {
+    pub fn parse(segment: &str) -> Option<Self> {
+        let segment = match segment {
+            "local" => Segment::Local,
+            "argument" => Segment::Argument,
+            "this" => Segment::This,
+            "that" => Segment::That,
+            "constant" => Segment::Constant,
+            "static" => Segment::Static,
+            "temp" => Segment::Temp,
+            "pointer" => Segment::Pointer,
+            _ => return None,
+        };
+        Some(segment)
+    }
 }
 
-#[derive(Debug)]
-pub enum MemSeg {
-    LCL,
-    ARG,
-    THIS,
-    THAT,
-    CONST,
-    STATIC,
-    PTR,
-    TEMP,
-    NONE,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
 }
 
-#[derive(Debug)]
-pub enum ArithOp {
-    ADD,
-    SUB,
-    NEG,
-    EQ,
-    GT,
-    LT,
-    AND,
-    OR,
-    NOT,
-    NONE,
+impl Op {
+    pub fn parse(op: &str) -> Option<Self> {
+        let op = match op {
+            "add" => Op::Add,
+            "sub" => Op::Sub,
+            "neg" => Op::Neg,
+            "eq" => Op::Eq,
+            "gt" => Op::Gt,
+            "lt" => Op::Lt,
+            "and" => Op::And,
+            "or" => Op::Or,
+            "not" => Op::Not,
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmCommand {
+    Arith(Op),
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function { name: String, n_locals: u16 },
+    Call { name: String, n_args: u16 },
+    Return,
+    // a command, segment or arithmetic op that is not part of the VM language,
+    // reported unless unknown commands are allowed
+    Unknown(Diagnostic),
+}
+
+// position of a line within its .vm file, raw is the line as written and
+// tokens are its words with their spans
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    pub file: String,
+    pub line_num: usize,
+    pub raw: String,
+    pub tokens: Vec<Token>,
+}
+
+impl Location {
+    pub fn new(file: &str, line_num: usize, raw: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            line_num,
+            raw: raw.to_string(),
+            tokens: tokenize(line_num, raw),
+        }
+    }
+
+    // the nth word of the line, eg. 2 is the index of push local 2
+    pub fn word(&self, nth: usize) -> &str {
+        self.tokens
+            .get(nth)
+            .map(|token| token.text.as_str())
+            .unwrap_or_default()
+    }
+
+    // the words of the line separated by a single space
+    pub fn source(&self) -> String {
+        let words: Vec<&str> = self
+            .tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+        words.join(" ")
+    }
+
+    // diagnostic at the nth word, a missing word points past the last one
+    pub fn diagnostic(&self, nth: usize, expected: &str) -> Diagnostic {
+        let column = match self.tokens.get(nth) {
+            Some(token) => token.span.column,
+            None => self.tokens.last().map_or(1, |token| token.span.end()),
+        };
+
+        Diagnostic::new(&self.file, self.line_num, column, self.word(nth), expected)
+    }
 }
 
 #[derive(Debug)]
 pub struct SourceLine {
     pub source: String,
-    pub args: Args,
-    pub mem_seg: MemSeg,
-    pub arith_op: ArithOp,
-    pub cmd_type: CommandType,
+    pub command: VmCommand,
+    pub location: Location,
 }
 
 impl SourceLine {
-    pub fn new(
-        source: &str,
-        args: Args,
-        mem_seg: MemSeg,
-        arith_op: ArithOp,
-        cmd_type: CommandType,
-    ) -> Self {
+    pub fn new(source: &str, command: VmCommand, location: Location) -> Self {
         Self {
             source: source.to_string(),
-            args,
-            mem_seg,
-            arith_op,
-            cmd_type,
+            command,
+            location,
         }
     }
+
+    pub fn word(&self, nth: usize) -> &str {
+        self.location.word(nth)
+    }
+
+    pub fn diagnostic(&self, nth: usize, expected: &str) -> Diagnostic {
+        self.location.diagnostic(nth, expected)
+    }
 }
 
 impl Display for SourceLine {
@@ -1,102 +1,246 @@
-#![allow(clippy::upper_case_acronyms)]
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+use hvm_translator::assembler::Assembler;
+use hvm_translator::code::{output_path, CodeWriter};
+use hvm_translator::emulator::Emulator;
+use hvm_translator::{tst, Asm, AsmReader, Error, Result, Translator};
+
+mod cli;
+
+use cli::{Cli, Command, Emit, Verbosity, HELP, USAGE};
+
+// exit status, a failed test or run, bad arguments, invalid VM code, asm or
+// templates, and files that cannot be read or written
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_PARSE: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+
+    let cli = match Cli::parse(&args) {
+        Ok(cli) => cli,
+        Err(msg) => {
+            eprintln!("error: {msg}\n{USAGE}");
+            process::exit(EXIT_USAGE);
+        }
+    };
 
-use std::io::Result;
-use std::{env, fs, fs::metadata, path::Path};
+    if let Err(err) = run(&cli) {
+        eprintln!("{err}");
+        process::exit(exit_code(&err));
+    }
+}
 
-mod asm;
-mod code;
-mod line;
-mod parser;
+fn exit_code(err: &Error) -> i32 {
+    match err {
+        Error::Io(_) => EXIT_IO,
+        Error::Emulator(_) => EXIT_FAILURE,
+        Error::Usage(_) => EXIT_USAGE,
+        Error::Diagnostics(_) | Error::Asm(_) | Error::Template(_) => EXIT_PARSE,
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    match cli.command {
+        Command::Help => {
+            println!("{USAGE}\n{HELP}");
+            return Ok(());
+        }
+        Command::Version => {
+            println!("hvm-translator {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Command::Asm => return assemble(cli),
+        _ => {}
+    }
 
-use asm::Asm;
-use code::{Code, CodeWriter};
-use parser::Parser;
+    // embedded templates, optionally overridden from a template directory
+    let templates = match &cli.template_dir {
+        Some(dir) => AsmReader::from_dir(dir)?,
+        None => AsmReader::new(),
+    };
 
-pub fn gen_init_asm(dir_name: &str) -> Vec<Asm> {
-    let mut asm_lines: Vec<Asm> = vec![];
+    if cli.command == Command::Test {
+        let filename = &cli.inputs[0];
+        let report = tst::run_script(filename, &templates)?;
 
-    // check if Sys.vm exists within directory
-    if Path::new(&format!("./{}/{}", dir_name, "Sys.vm")).exists() {
-        let mut code = Code::new("SysInitBootstrap");
-        let bootstrap = code.gen_init_asm();
+        if cli.verbosity > Verbosity::Quiet {
+            for warning in &report.warnings {
+                eprintln!("{warning}");
+            }
+            for text in &report.echo {
+                println!("{text}");
+            }
+        }
 
-        asm_lines.push(bootstrap);
+        return match report.failure {
+            None => {
+                if cli.verbosity > Verbosity::Quiet {
+                    println!("End of script - Comparison ended successfully");
+                }
+                Ok(())
+            }
+            Some(failure) => {
+                eprintln!("{}: {failure}", filename.display());
+                process::exit(EXIT_FAILURE);
+            }
+        };
+    }
 
-        // write Sys init to asm first
-        let mut sys_init_asm = gen_asm(&format!("./{}/{}", dir_name, "Sys.vm")).unwrap();
+    let translation = translator(cli, templates)?.translate()?;
 
-        asm_lines.append(&mut sys_init_asm);
+    if cli.verbosity > Verbosity::Quiet {
+        for warning in &translation.warnings {
+            eprintln!("{warning}");
+        }
     }
+    let asm_ins = translation.asm;
 
-    asm_lines
+    match cli.command {
+        Command::Check => {
+            if cli.verbosity == Verbosity::Verbose {
+                eprintln!("{} commands checked", asm_ins.len());
+            }
+            Ok(())
+        }
+        Command::Run => execute(cli, &asm_ins),
+        _ => emit(cli, &asm_ins),
+    }
 }
 
-pub fn gen_asm(filename: &str) -> Result<Vec<Asm>> {
-    let parser = Parser::new(filename);
+// the translator configured from the command line with every input added
+fn translator(cli: &Cli, templates: AsmReader) -> Result<Translator> {
+    let order: Vec<&str> = cli.order.iter().map(|module| module.as_str()).collect();
 
-    let src_lines = parser.read_lines()?;
+    let mut translator = Translator::new()
+        .templates(templates)
+        .allow_unknown(cli.allow_unknown)
+        .order(&order)
+        .entry(&cli.entry)
+        .stack_base(cli.stack_base);
+
+    if let Some(bootstrap) = cli.bootstrap {
+        translator = translator.bootstrap(bootstrap);
+    }
+
+    for input in &cli.inputs {
+        if cli.verbosity == Verbosity::Verbose {
+            eprintln!("reading {}", input.display());
+        }
+
+        translator = match input.to_str() {
+            Some("-") => translator.reader(&cli.module, io::stdin().lock())?,
+            _ => translator.input(input),
+        };
+    }
 
-    let mut asm_ins: Vec<Asm> = Vec::new();
+    for dir in &cli.lib_paths {
+        translator = translator.lib_path(dir);
+    }
 
-    let base_fn = Path::new(&filename).file_name().unwrap().to_str().unwrap();
+    Ok(translator)
+}
 
-    let base_fn = base_fn.split('.').collect::<Vec<&str>>();
+// write every format asked for by --emit
+fn emit(cli: &Cli, asm_ins: &[Asm]) -> Result<()> {
+    for emit in &cli.emit {
+        match emit {
+            Emit::Asm => {
+                let mut writer = writer(cli, "asm")?;
 
-    let mut code = Code::new(base_fn.get(0).unwrap());
+                for asm in asm_ins {
+                    writer.write_asm(asm)?;
+                }
+            }
+            Emit::Hack => {
+                let words = Assembler::new().assemble(asm_ins)?;
 
-    for line in src_lines {
-        let asm = code.gen_asm(&line);
-        asm_ins.push(asm);
+                let mut writer = writer(cli, "hack")?;
+                writer.write_hack(&words)?;
+            }
+        }
     }
 
-    Ok(asm_ins)
+    Ok(())
 }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+// run the program on the CPU emulator and print SP and the stack
+fn execute(cli: &Cli, asm_ins: &[Asm]) -> Result<()> {
+    let mut emulator = Emulator::load(asm_ins)?;
 
-    // Main logic to run program
-    if let Some(filename) = args.get(1) {
-        // check if filename is directory or string
-        let meta = metadata(filename).unwrap();
+    // the bootstrap sets SP itself, programs without one expect it set
+    emulator.ram[0] = cli.stack_base as i16;
 
-        let asm_ins = if meta.is_dir() {
-            let paths = fs::read_dir(format!("./{}", filename)).unwrap();
+    let cycles = emulator.run(cli.cycles)?;
 
-            let mut asm_lines = gen_init_asm(filename);
+    if !emulator.is_halted() {
+        eprintln!("error: the program did not halt after {cycles} cycles");
+        process::exit(EXIT_FAILURE);
+    }
 
-            // walk directory
-            for entry in paths {
-                let dir_entry = entry?;
-                let path = dir_entry.path();
-                let filename = path.to_str().unwrap();
+    if cli.verbosity > Verbosity::Quiet {
+        eprintln!("halted after {cycles} cycles");
+    }
 
-                // skip Sys.vm
-                if filename.contains("Sys.vm") {
-                    continue;
-                }
+    let sp = emulator.ram[0];
+    println!("RAM[0] = {sp}");
 
-                // only handle vm extensions
-                if filename.ends_with("vm") {
-                    let mut file_asm = gen_asm(filename)?;
+    for addr in cli.stack_base as usize..(sp.max(0) as usize).min(emulator.ram.len()) {
+        println!("RAM[{addr}] = {}", emulator.ram[addr]);
+    }
 
-                    // append file asm to global asm
-                    asm_lines.append(&mut file_asm);
-                }
-            }
-            asm_lines
-        } else {
-            gen_asm(filename)?
-        };
+    Ok(())
+}
 
-        let mut writer = CodeWriter::new(filename)?;
+// assemble a .asm file, or asm read from stdin, into .hack
+fn assemble(cli: &Cli) -> Result<()> {
+    let input = &cli.inputs[0];
 
-        for asm in asm_ins {
-            writer.write_asm(&asm)?;
-        }
+    let source = if cli.is_stdin() {
+        let mut source = String::new();
+        io::stdin().lock().read_to_string(&mut source)?;
+        source
     } else {
-        println!("There was an error parsing the args, please provide filename")
-    }
+        fs::read_to_string(input)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", input.display())))?
+    };
+
+    let lines: Vec<&str> = source.lines().collect();
+    let words = Assembler::new().assemble_lines(&lines)?;
+
+    let mut writer = writer(cli, "hack")?;
+    writer.write_hack(&words)?;
 
     Ok(())
 }
+
+// where an emitted format is written, None for stdout
+fn output(cli: &Cli, extension: &str) -> io::Result<Option<PathBuf>> {
+    match &cli.output {
+        Some(output) if output == Path::new("-") => Ok(None),
+        // the exact path when a single format is emitted
+        Some(output) if cli.emit.len() == 1 => Ok(Some(output.clone())),
+        Some(output) => Ok(Some(output.with_extension(extension))),
+        None if cli.is_stdin() => Ok(None),
+        None => Ok(Some(output_path(&cli.inputs[0], extension)?)),
+    }
+}
+
+// - and stdin input write to stdout
+fn writer(cli: &Cli, extension: &str) -> io::Result<CodeWriter> {
+    match output(cli, extension)? {
+        Some(path) => {
+            if cli.verbosity == Verbosity::Verbose {
+                eprintln!("writing {}", path.display());
+            }
+            CodeWriter::create(&path)
+        }
+        None => Ok(CodeWriter::stdout()),
+    }
+}
@@ -1,222 +1,380 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Result};
+use std::path::{Path, PathBuf};
 
-use crate::line::{Args, ArithOp, CommandType, MemSeg, SourceLine};
+use crate::error::{self, Diagnostic};
+use crate::lexer::tokenize;
+use crate::line::{Location, Op, Segment, SourceLine, VmCommand};
+use crate::translator::with_path;
+
+const MEM_SEGS: &str =
+    "a memory segment (local, argument, this, that, constant, static, temp, pointer)";
+
+const ARITH_OPS: &str = "an arithmetic command (add, sub, neg, eq, gt, lt, and, or, not)";
+
+const IDENTIFIER: &str = "a name of letters, digits, _, . and :, not starting with a digit";
 
 pub struct Parser {
-    in_fn: String, // pub lines: Vec<SourceLine>
+    path: PathBuf,
 }
 
 impl Parser {
-    pub fn new(in_fn: &str) -> Self {
+    pub fn new(path: impl AsRef<Path>) -> Self {
         Self {
-            in_fn: in_fn.to_string(),
+            path: path.as_ref().to_path_buf(),
         }
     }
 
-    pub fn read_lines(&self) -> Result<Vec<SourceLine>> {
-        let mut return_lines: Vec<SourceLine> = vec![];
+    // parse every line of the file, all diagnostics found are returned together
+    pub fn read_lines(&self) -> error::Result<Vec<SourceLine>> {
+        let path = &self.path;
+        let source = fs::read_to_string(path).map_err(|err| with_path(path, err))?;
+
+        // diagnostics name the file as given, non UTF-8 parts are replaced
+        Parser::parse_source(&path.display().to_string(), &source)
+    }
 
-        let lines: Vec<String> = LineParser::parse_lines(&self.in_fn)?;
+    // parse VM source held in memory, filename is only used for diagnostics
+    pub fn parse_source(filename: &str, source: &str) -> error::Result<Vec<SourceLine>> {
+        Parser::parse_reader(filename, source.as_bytes())
+    }
 
-        for source in lines {
-            // get command type
-            let cmd_type = self.get_cmd_type(&source);
+    // parse VM source from any reader, eg. stdin
+    pub fn parse_reader<R: BufRead>(filename: &str, reader: R) -> error::Result<Vec<SourceLine>> {
+        let mut return_lines: Vec<SourceLine> = vec![];
+        let mut diagnostics: Vec<Diagnostic> = vec![];
 
-            // get args
-            let args = self.get_args(&source, &cmd_type);
+        let lines = LineParser::parse_located_lines(filename, reader)?;
 
-            let mem_seg = self.get_mem_seg(&args, &cmd_type);
+        for location in lines {
+            let source = location.source();
 
-            let arith_op = self.get_arith_op(&args, &cmd_type);
+            match Parser::parse_command(&location) {
+                Ok(command) => return_lines.push(SourceLine::new(&source, command, location)),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
 
-            let line = SourceLine::new(&source, args, mem_seg, arith_op, cmd_type);
-            return_lines.push(line);
+        if diagnostics.is_empty() {
+            Ok(return_lines)
+        } else {
+            Err(diagnostics.into())
         }
-        Ok(return_lines)
-    }
-
-    fn get_mem_seg(&self, args: &Args, cmd_type: &CommandType) -> MemSeg {
-        let mem_seg = match cmd_type {
-            CommandType::POP | CommandType::PUSH => {
-                let seg = match args.arg1.as_str() {
-                    "local" => MemSeg::LCL,
-                    "argument" => MemSeg::ARG,
-                    "this" => MemSeg::THIS,
-                    "that" => MemSeg::THAT,
-                    "constant" => MemSeg::CONST,
-                    "static" => MemSeg::STATIC,
-                    "temp" => MemSeg::TEMP,
-                    "pointer" => MemSeg::PTR,
-                    _ => MemSeg::NONE,
-                };
-                seg
-            }
-            _ => MemSeg::NONE,
+    }
+
+    // a single line, malformed commands are errors while unknown commands,
+    // segments and ops are kept for --allow-unknown
+    pub fn parse_command(location: &Location) -> std::result::Result<VmCommand, Diagnostic> {
+        let line_spl: Vec<&str> = location
+            .tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+
+        let Some(&cmd) = line_spl.first() else {
+            return Err(location.diagnostic(0, "a VM command"));
         };
-        mem_seg
-    }
-
-    fn get_arith_op(&self, args: &Args, cmd_type: &CommandType) -> ArithOp {
-        let arith_op = match cmd_type {
-            CommandType::ARITHMETIC => {
-                let seg = match args.arg1.as_str() {
-                    "add" => ArithOp::ADD,
-                    "sub" => ArithOp::SUB,
-                    "neg" => ArithOp::NEG,
-                    "eq" => ArithOp::EQ,
-                    "gt" => ArithOp::GT,
-                    "lt" => ArithOp::LT,
-                    "and" => ArithOp::AND,
-                    "or" => ArithOp::OR,
-                    "not" => ArithOp::NOT,
-                    _ => ArithOp::NONE,
+
+        let command = match cmd {
+            "push" | "pop" => {
+                let form = format!("`{cmd} <segment> <index>`");
+                Parser::expect_words(location, 3, &form)?;
+                let index = Parser::parse_index(location, 2)?;
+
+                match (Segment::parse(line_spl[1]), cmd) {
+                    (Some(segment), "push") => VmCommand::Push(segment, index),
+                    (Some(segment), _) => VmCommand::Pop(segment, index),
+                    (None, _) => VmCommand::Unknown(location.diagnostic(1, MEM_SEGS)),
+                }
+            }
+
+            "function" | "call" => {
+                let form = match cmd {
+                    "function" => "`function <name> <n_locals>`",
+                    _ => "`call <name> <n_args>`",
                 };
-                seg
+                Parser::expect_words(location, 3, form)?;
+                let name = Parser::parse_name(location, 1)?;
+                let count = Parser::parse_index(location, 2)?;
+
+                match cmd {
+                    "function" => VmCommand::Function {
+                        name,
+                        n_locals: count,
+                    },
+                    _ => VmCommand::Call {
+                        name,
+                        n_args: count,
+                    },
+                }
             }
-            _ => ArithOp::NONE,
-        };
-        arith_op
-    }
 
-    fn get_args(&self, source: &str, cmd_type: &CommandType) -> Args {
-        let line_spl: Vec<&str> = source.split(' ').collect();
-        let args = match cmd_type {
-            // arithmetic args
-            CommandType::ARITHMETIC => Args {
-                arg1: line_spl.get(0).unwrap().to_string(),
-                arg2: None,
-            },
+            "label" | "goto" | "if-goto" => {
+                Parser::expect_words(location, 2, &format!("`{cmd} <label>`"))?;
+                let label = Parser::parse_name(location, 1)?;
 
-            // push pop args
-            CommandType::POP | CommandType::PUSH | CommandType::FUNCTION | CommandType::CALL => {
-                Args {
-                    arg1: line_spl.get(1).unwrap().to_string(),
-                    arg2: line_spl.get(2).map(|val| val.parse::<i32>().unwrap()),
-                    // arg2: match line_spl.get(2) {
-                    //     Some(val) => Some(val.parse::<i32>().unwrap()),
-                    //     None => None,
-                    // },
+                match cmd {
+                    "label" => VmCommand::Label(label),
+                    "goto" => VmCommand::Goto(label),
+                    _ => VmCommand::IfGoto(label),
                 }
             }
 
-            // push pop args
-            CommandType::IF | CommandType::GOTO | CommandType::LABEL => Args {
-                arg1: line_spl.get(1).unwrap().to_string(),
-                arg2: None,
-            },
+            "return" => {
+                Parser::expect_words(location, 1, "`return`")?;
+                VmCommand::Return
+            }
 
-            CommandType::RETURN => Args {
-                arg1: line_spl.get(0).unwrap().to_string(),
-                arg2: None,
+            // any other single word is an arithmetic command
+            _ if line_spl.len() == 1 => match Op::parse(cmd) {
+                Some(op) => VmCommand::Arith(op),
+                None => VmCommand::Unknown(location.diagnostic(0, ARITH_OPS)),
             },
 
-            //  => Args {
-            //     arg1: line_spl.get(1).unwrap().to_string(),
-            //     arg2: None,
-            // },
-
-            // unknown args
-            _ => Args {
-                arg1: "".to_string(),
-                arg2: None,
-            },
+            // any other command type
+            _ => VmCommand::Unknown(location.diagnostic(0, "a VM command")),
         };
-        args
+        Ok(command)
     }
 
-    fn get_cmd_type(&self, source: &str) -> CommandType {
-        let line_split: Vec<&str> = source.split(' ').collect();
-        let cmd_type = match line_split.len() {
-            1 => match line_split.get(0) {
-                Some(&"return") => CommandType::RETURN,
-                _ => CommandType::ARITHMETIC,
-            },
-            2 => {
-                let c_type = match line_split.get(0) {
-                    Some(&"label") => CommandType::LABEL,
-                    Some(&"if-goto") => CommandType::IF,
-                    Some(&"goto") => CommandType::GOTO,
-                    _ => CommandType::UNKOWN,
-                };
-                c_type
-            }
-            3 => {
-                let c_type = match line_split.get(0) {
-                    Some(&"pop") => CommandType::POP,
-                    Some(&"push") => CommandType::PUSH,
-                    Some(&"call") => CommandType::CALL,
-                    Some(&"function") => CommandType::FUNCTION,
-                    _ => CommandType::UNKOWN,
-                };
-                c_type
-            }
-            // any other command type
-            _ => CommandType::UNKOWN,
-        };
-        cmd_type
+    // check the command has exactly count words, including the command itself
+    fn expect_words(
+        location: &Location,
+        count: usize,
+        form: &str,
+    ) -> std::result::Result<(), Diagnostic> {
+        let words = location.tokens.len();
+
+        if words < count {
+            return Err(location.diagnostic(words, form));
+        }
+
+        if words > count {
+            return Err(location.diagnostic(count, &format!("end of line after {form}")));
+        }
+
+        Ok(())
+    }
+
+    // the nth word as a function or label name, names end up as asm symbols
+    fn parse_name(location: &Location, nth: usize) -> std::result::Result<String, Diagnostic> {
+        let name = location.word(nth);
+
+        let valid = !name.starts_with(|c: char| c.is_ascii_digit())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.:".contains(c));
+
+        if valid {
+            Ok(name.to_string())
+        } else {
+            Err(location.diagnostic(nth, IDENTIFIER))
+        }
+    }
+
+    // the nth word as a segment index or count
+    fn parse_index(location: &Location, nth: usize) -> std::result::Result<u16, Diagnostic> {
+        match location.word(nth).parse::<u16>() {
+            Ok(index) if index <= 32767 => Ok(index),
+            _ => Err(location.diagnostic(nth, "an integer between 0 and 32767")),
+        }
     }
 }
 
 pub struct LineParser {}
 
 impl LineParser {
-    pub fn parse_lines(filename: &str) -> Result<Vec<String>> {
+    pub fn parse_lines(filename: impl AsRef<Path>) -> Result<Vec<String>> {
         let file = File::open(filename)?;
 
         let buf = BufReader::new(file).lines();
 
         let mut lines: Vec<String> = vec![];
 
-        for source_line in buf.flatten() {
-            if source_line.starts_with('/') || source_line.is_empty() {
-                continue;
-            } else {
-                // remove comments
-                let no_comment_src = LineParser::strip_comments(&source_line);
-
-                // remove white space
-                let words = LineParser::strip_white_space(&no_comment_src);
-
-                // join words
-                let final_source = LineParser::join_words(words);
+        for source_line in buf {
+            if let Some(final_source) = LineParser::parse_line(&source_line?) {
                 lines.push(final_source);
             }
         }
         Ok(lines)
     }
 
-    pub fn strip_comments(line: &str) -> String {
-        // check if line has comment
-        let line_spl: Vec<&str> = line.split("//").collect();
-        line_spl.get(0).unwrap().to_string()
-        // line.to_string()
-    }
+    // like parse_lines, keeps the location of every line holding a word
+    pub fn parse_located_lines<R: BufRead>(filename: &str, reader: R) -> Result<Vec<Location>> {
+        let mut lines: Vec<Location> = vec![];
 
-    pub fn strip_white_space(line: &str) -> Vec<String> {
-        let mut words = vec![];
+        for (idx, source_line) in reader.lines().enumerate() {
+            let location = Location::new(filename, idx + 1, &source_line?);
 
-        for word in line.split(' ') {
-            if !word.is_empty() {
-                words.push(word.trim().to_string());
+            if !location.tokens.is_empty() {
+                lines.push(location);
             }
         }
+        Ok(lines)
+    }
 
-        words
+    // parse source already held in memory, eg. embedded asm templates
+    pub fn parse_source(source: &str) -> Vec<String> {
+        source.lines().filter_map(LineParser::parse_line).collect()
     }
 
-    pub fn join_words(words: Vec<String>) -> String {
-        words.join(" ")
+    // the words of a line without comments, None for blank and comment lines
+    pub fn parse_line(source_line: &str) -> Option<String> {
+        let words: Vec<String> = tokenize(0, source_line)
+            .into_iter()
+            .map(|token| token.text)
+            .collect();
+
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::error::Error;
+    use crate::test_dir;
+    use std::fs;
 
     #[test]
     fn test_line_parser() -> Result<()> {
         // let lines = LineParser::parse_lines("push_const.asm")?;
         Ok(())
     }
+
+    #[test]
+    fn test_parse_locations() {
+        let source = "// header\n\npush constant 7\nadd\n";
+
+        let lines = Parser::parse_source("Main.vm", source).unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].location.line_num, 3);
+        assert_eq!(lines[0].command, VmCommand::Push(Segment::Constant, 7));
+        assert_eq!(lines[1].location.line_num, 4);
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let parse =
+            |source: &str| Parser::parse_command(&Location::new("Test.vm", 1, source)).unwrap();
+
+        assert_eq!(parse("pop that 3"), VmCommand::Pop(Segment::That, 3));
+        assert_eq!(parse("gt"), VmCommand::Arith(Op::Gt));
+        assert_eq!(parse("if-goto END"), VmCommand::IfGoto("END".to_string()));
+        assert_eq!(
+            parse("call Math.max 2"),
+            VmCommand::Call {
+                name: "Math.max".to_string(),
+                n_args: 2
+            }
+        );
+        assert_eq!(parse("return"), VmCommand::Return);
+
+        // unknown commands are kept with the reason they are unknown
+        let VmCommand::Unknown(diagnostic) = parse("push stack 1") else {
+            panic!("expected an unknown segment");
+        };
+        assert_eq!(diagnostic.token, "stack");
+        assert!(matches!(parse("add1"), VmCommand::Unknown(_)));
+
+        // blank and comment only lines hold no command
+        let diagnostic =
+            Parser::parse_command(&Location::new("Test.vm", 1, "  // note")).unwrap_err();
+        assert_eq!(diagnostic.token, "");
+        assert_eq!(diagnostic.expected, "a VM command");
+    }
+
+    #[test]
+    fn test_parse_names() {
+        let parse = |source: &str| Parser::parse_command(&Location::new("Test.vm", 1, source));
+
+        assert!(parse("function Main.main:1 0").is_ok());
+
+        // names end up as asm symbols, 9lives would be read as an address
+        for source in ["call 9lives 0", "function Foo-bar 0", "label a-b", "goto 1"] {
+            let diagnostic = parse(source).unwrap_err();
+            assert_eq!(diagnostic.expected, IDENTIFIER, "{source}");
+            assert_eq!(diagnostic.column, source.find(' ').unwrap() + 2);
+        }
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        let source = "push constant 7\n\n// comment\npop static 1\n";
+
+        let lines = Parser::parse_reader("stdin", std::io::Cursor::new(source)).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].location.line_num, 4);
+        assert_eq!(lines[1].command, VmCommand::Pop(Segment::Static, 1));
+
+        let err = Parser::parse_source("Main", "push constant x\n").unwrap_err();
+        assert!(err.to_string().starts_with("Main:1:15: error"));
+    }
+
+    #[test]
+    fn test_parse_tabs_and_crlf() {
+        let source = "\t// header\r\n\tpush\tconstant 7\r\n  \t// indented\r\nadd // sum\r\n\tpop\tlocal x\r\n";
+
+        let err = Parser::parse_source("Main.vm", source).unwrap_err();
+        let Error::Diagnostics(diagnostics) = err else {
+            panic!("expected diagnostics, got {err:?}");
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (5, 12));
+
+        let lines = Parser::parse_source("Main.vm", &source.replace(" x", " 0")).unwrap();
+        let commands: Vec<&VmCommand> = lines.iter().map(|line| &line.command).collect();
+        assert_eq!(
+            commands,
+            vec![
+                &VmCommand::Push(Segment::Constant, 7),
+                &VmCommand::Arith(Op::Add),
+                &VmCommand::Pop(Segment::Local, 0),
+            ]
+        );
+        assert_eq!(lines[0].source, "push constant 7");
+    }
+
+    // diagnostics of a file name it as given
+    #[test]
+    fn test_read_lines_collects_diagnostics() {
+        let source = "push constant x\npush local\nadd\npop local 1 2\n  goto\n";
+        let dir = test_dir("parser_diagnostics");
+        let path = dir.join("Test.vm");
+        fs::write(&path, source).unwrap();
+        let filename = path.display().to_string();
+
+        let diagnostics = match Parser::new(&filename).read_lines() {
+            Err(Error::Diagnostics(diagnostics)) => diagnostics,
+            other => panic!("expected diagnostics, got {other:?}"),
+        };
+
+        assert_eq!(diagnostics.len(), 4);
+
+        assert_eq!(diagnostics[0].line, 1);
+        assert_eq!(diagnostics[0].column, 15);
+        assert_eq!(diagnostics[0].token, "x");
+        assert_eq!(diagnostics[0].expected, "an integer between 0 and 32767");
+
+        assert_eq!(diagnostics[1].line, 2);
+        assert_eq!(diagnostics[1].column, 11);
+        assert_eq!(diagnostics[1].token, "");
+        assert_eq!(diagnostics[1].expected, "`push <segment> <index>`");
+
+        assert_eq!(diagnostics[2].line, 4);
+        assert_eq!(diagnostics[2].token, "2");
+
+        assert_eq!(diagnostics[3].line, 5);
+        assert_eq!(diagnostics[3].column, 7);
+        assert_eq!(
+            diagnostics[3].to_string(),
+            format!("{filename}:5:7: error: expected `goto <label>`, found end of line")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
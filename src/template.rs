@@ -0,0 +1,289 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::assembler::AsmError;
+use crate::instruction::Instruction;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateErrorKind {
+    // used by the template, not known to its generator
+    Unknown,
+    // filled by the generator, not used by the template
+    Missing,
+    // used by the template, no value given
+    Unfilled,
+    // a line that is not a Hack instruction, placeholder is empty
+    Invalid(AsmError),
+}
+
+// a template that does not have the placeholders its generator fills
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub template: String,
+    pub placeholder: String,
+    pub kind: TemplateErrorKind,
+}
+
+impl TemplateError {
+    pub fn new(template: &str, placeholder: &str, kind: TemplateErrorKind) -> Self {
+        Self {
+            template: template.to_string(),
+            placeholder: placeholder.to_string(),
+            kind,
+        }
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (template, placeholder) = (&self.template, &self.placeholder);
+
+        match &self.kind {
+            TemplateErrorKind::Unknown => {
+                write!(
+                    f,
+                    "template {template}: unknown placeholder `${placeholder}`"
+                )
+            }
+            TemplateErrorKind::Missing => {
+                write!(
+                    f,
+                    "template {template}: missing placeholder `${placeholder}`"
+                )
+            }
+            TemplateErrorKind::Unfilled => {
+                write!(
+                    f,
+                    "template {template}: placeholder `${placeholder}` is not filled"
+                )
+            }
+            TemplateErrorKind::Invalid(err) => write!(f, "template {template}: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+// a line split into plain text and $PLACEHOLDER names
+fn split(line: &str) -> Vec<(&str, bool)> {
+    let mut parts = vec![];
+    let mut rest = line;
+
+    while let Some(start) = rest.find('$') {
+        let name_len = rest[start + 1..]
+            .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(rest.len() - start - 1);
+
+        // a lone $ is plain text, eg. Foo$ret.1
+        if name_len == 0 {
+            parts.push((&rest[..=start], false));
+            rest = &rest[start + 1..];
+            continue;
+        }
+
+        parts.push((&rest[..start], false));
+        parts.push((&rest[start + 1..start + 1 + name_len], true));
+        rest = &rest[start + 1 + name_len..];
+    }
+    parts.push((rest, false));
+
+    parts
+}
+
+// placeholder names of a template in order of first use
+pub fn placeholders(lines: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+
+    for line in lines {
+        for (name, is_placeholder) in split(line) {
+            if is_placeholder && !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names
+}
+
+// the template must use exactly the placeholders its generator fills
+pub fn check(template: &str, lines: &[String], expected: &[&str]) -> Result<(), TemplateError> {
+    let names = placeholders(lines);
+
+    if let Some(unknown) = names.iter().find(|name| !expected.contains(&name.as_str())) {
+        return Err(TemplateError::new(
+            template,
+            unknown,
+            TemplateErrorKind::Unknown,
+        ));
+    }
+
+    match expected
+        .iter()
+        .find(|name| !names.iter().any(|known| known == *name))
+    {
+        Some(missing) => Err(TemplateError::new(
+            template,
+            missing,
+            TemplateErrorKind::Missing,
+        )),
+        None => Ok(()),
+    }
+}
+
+// every line must be a Hack instruction once filled, lines that are a single
+// placeholder are filled with whole instructions by AsmGen
+pub fn check_instructions(template: &str, lines: &[String]) -> Result<(), TemplateError> {
+    for line in lines {
+        let parts = split(line);
+
+        if let [("", false), (_, true), ("", false)] = parts[..] {
+            continue;
+        }
+
+        // inline placeholders hold labels and constants, a symbol stands in
+        let sample: String = parts
+            .iter()
+            .map(|(text, is_placeholder)| if *is_placeholder { "PLACEHOLDER" } else { text })
+            .collect();
+
+        Instruction::parse(&sample)
+            .map_err(|err| TemplateError::new(template, "", TemplateErrorKind::Invalid(err)))?;
+    }
+    Ok(())
+}
+
+// substitute every placeholder by name, values are not scanned again
+pub fn fill(
+    template: &str,
+    lines: &[String],
+    values: &[(&str, &str)],
+) -> Result<Vec<String>, TemplateError> {
+    let mut filled = vec![];
+
+    for line in lines {
+        let mut out = String::new();
+
+        for (text, is_placeholder) in split(line) {
+            if !is_placeholder {
+                out.push_str(text);
+                continue;
+            }
+
+            match values.iter().find(|(name, _)| *name == text) {
+                Some((_, value)) => out.push_str(value),
+                None => {
+                    return Err(TemplateError::new(
+                        template,
+                        text,
+                        TemplateErrorKind::Unfilled,
+                    ))
+                }
+            }
+        }
+        filled.push(out);
+    }
+
+    for (name, _) in values {
+        if !lines.iter().any(|line| split(line).contains(&(name, true))) {
+            return Err(TemplateError::new(
+                template,
+                name,
+                TemplateErrorKind::Unknown,
+            ));
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_fill_placeholders() {
+        let template = lines(&[
+            "@$TRUE_LABEL",
+            "$COMPARE_COMMAND",
+            "($TRUE_LABEL)",
+            "@Foo$ret.1",
+        ]);
+
+        assert_eq!(
+            placeholders(&template),
+            vec!["TRUE_LABEL", "COMPARE_COMMAND"]
+        );
+
+        let filled = fill(
+            "cmp.asm",
+            &template,
+            &[("TRUE_LABEL", "TRUE_1"), ("COMPARE_COMMAND", "D;JEQ")],
+        )
+        .unwrap();
+        assert_eq!(filled, vec!["@TRUE_1", "D;JEQ", "(TRUE_1)", "@Foo$ret.1"]);
+
+        // values that look like placeholders are kept as they are
+        let filled = fill("if_goto.asm", &lines(&["$LABEL"]), &[("LABEL", "@$END")]).unwrap();
+        assert_eq!(filled, vec!["@$END"]);
+    }
+
+    #[test]
+    fn test_fill_errors() {
+        let template = lines(&["@$CONST_VAL", "D=A"]);
+
+        let err = fill("push_const.asm", &template, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template push_const.asm: placeholder `$CONST_VAL` is not filled"
+        );
+
+        let err = fill(
+            "push_const.asm",
+            &template,
+            &[("CONST_VAL", "7"), ("VALUE", "7")],
+        )
+        .unwrap_err();
+        assert_eq!(err.kind, TemplateErrorKind::Unknown);
+        assert_eq!(err.placeholder, "VALUE");
+    }
+
+    #[test]
+    fn test_check_instructions() {
+        let template = lines(&["$NEG_COMMAND", "@$TRUE_LABEL", "($TRUE_LABEL)", "AM=M-1"]);
+        assert!(check_instructions("cmp.asm", &template).is_ok());
+
+        // the en-dash of a comment left in a template
+        let err = check_instructions("neg.asm", &lines(&["M=–D"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template neg.asm: invalid instruction `M=–D`, expected a valid comp mnemonic"
+        );
+
+        assert!(check_instructions("push_const.asm", &lines(&["D$CONST_VAL"])).is_err());
+    }
+
+    #[test]
+    fn test_check_placeholders() {
+        let template = lines(&["@$CONST_VAL", "D=A"]);
+
+        assert!(check("push_const.asm", &template, &["CONST_VAL"]).is_ok());
+
+        let err = check("push_const.asm", &template, &[]).unwrap_err();
+        assert_eq!(err.kind, TemplateErrorKind::Unknown);
+
+        let err = check("push_const.asm", &template, &["CONST_VAL", "OTHER"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template push_const.asm: missing placeholder `$OTHER`"
+        );
+
+        let err = check("push_const.asm", &lines(&["@$VALUE"]), &["CONST_VAL"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "template push_const.asm: unknown placeholder `$VALUE`"
+        );
+    }
+}
@@ -0,0 +1,757 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::asm::{Asm, AsmReader};
+use crate::assembler::check_labels;
+use crate::code::Code;
+use crate::error::{Diagnostic, Error, Result};
+use crate::instruction::Instruction;
+use crate::lexer::tokenize;
+use crate::line::{SourceLine, VmCommand};
+use crate::parser::Parser;
+
+// where the VM commands of an input come from
+enum Input {
+    // a .vm file or a directory of .vm files
+    Path(PathBuf),
+    // VM source held in memory, module names its static variables
+    Source { module: String, source: String },
+}
+
+// a single translation unit, one .vm file
+struct Module {
+    name: String,
+    lines: Vec<SourceLine>,
+}
+
+impl Module {
+    fn functions(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match &line.command {
+            VmCommand::Function { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    fn calls(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match &line.command {
+            VmCommand::Call { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+    }
+}
+
+// a .vm file found on the library path and the functions it defines
+struct LibraryFile {
+    path: PathBuf,
+    functions: Vec<String>,
+    linked: bool,
+}
+
+// the translated program and the warnings found while translating it
+#[derive(Debug)]
+pub struct Translation {
+    pub asm: Vec<Asm>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Translation {
+    // the program as .asm text
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for asm in &self.asm {
+            write!(out, "{asm}").unwrap();
+        }
+        out
+    }
+}
+
+// translates one VM program made of files, directories and in-memory sources
+//
+//     let translation = Translator::new()
+//         .source("Main", "push constant 7\npush constant 8\nadd\n")
+//         .translate()?;
+pub struct Translator {
+    inputs: Vec<Input>,
+    templates: AsmReader,
+    // None emits the bootstrap when one of the modules defines the entry function
+    bootstrap: Option<bool>,
+    // function called by the bootstrap
+    entry: String,
+    // SP set by the bootstrap
+    stack_base: u16,
+    allow_unknown: bool,
+    // modules translated first and in this order, eg. Main
+    order: Vec<String>,
+    // directories searched for the functions a program calls but does not define
+    lib_paths: Vec<PathBuf>,
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Self {
+            inputs: vec![],
+            templates: AsmReader::new(),
+            bootstrap: None,
+            entry: "Sys.init".to_string(),
+            stack_base: 256,
+            allow_unknown: false,
+            order: vec![],
+            lib_paths: vec![],
+        }
+    }
+
+    // a .vm file or every .vm file of a directory, Sys.vm first
+    pub fn input(mut self, path: impl AsRef<Path>) -> Self {
+        self.inputs.push(Input::Path(path.as_ref().to_path_buf()));
+        self
+    }
+
+    // VM source of the module, eg. Main for the contents of Main.vm
+    pub fn source(mut self, module: &str, source: &str) -> Self {
+        self.inputs.push(Input::Source {
+            module: module.to_string(),
+            source: source.to_string(),
+        });
+        self
+    }
+
+    // VM source read to the end of reader, eg. stdin
+    pub fn reader(self, module: &str, mut reader: impl Read) -> io::Result<Self> {
+        let mut source = String::new();
+        reader.read_to_string(&mut source)?;
+
+        Ok(self.source(module, &source))
+    }
+
+    // force the bootstrap on or off
+    pub fn bootstrap(mut self, bootstrap: bool) -> Self {
+        self.bootstrap = Some(bootstrap);
+        self
+    }
+
+    // the function called by the bootstrap, Sys.init by default
+    pub fn entry(mut self, entry: &str) -> Self {
+        self.entry = entry.to_string();
+        self
+    }
+
+    // the initial SP set by the bootstrap, 256 by default
+    pub fn stack_base(mut self, stack_base: u16) -> Self {
+        self.stack_base = stack_base;
+        self
+    }
+
+    // emit //UNKOWN comments for unknown commands instead of failing
+    pub fn allow_unknown(mut self, allow_unknown: bool) -> Self {
+        self.allow_unknown = allow_unknown;
+        self
+    }
+
+    // translate the named modules first in the given order, the others follow
+    // Sys first and then by file name
+    pub fn order(mut self, modules: &[&str]) -> Self {
+        self.order = modules.iter().map(|module| module.to_string()).collect();
+        self
+    }
+
+    // search dir and its subdirectories for called functions, a library file
+    // is only translated when the program calls one of its functions
+    pub fn lib_path(mut self, dir: impl AsRef<Path>) -> Self {
+        self.lib_paths.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn templates(mut self, templates: AsmReader) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    // translate every input in order, all diagnostics of all modules are returned together
+    pub fn translate(&self) -> Result<Translation> {
+        let mut modules = vec![];
+        let mut diagnostics = vec![];
+
+        for input in &self.inputs {
+            collect_modules(input, &mut modules, &mut diagnostics)?;
+        }
+
+        self.link(&mut modules, &mut diagnostics)?;
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics.into());
+        }
+
+        self.sort_modules(&mut modules)?;
+
+        let mut code = Code::new("", &self.templates);
+        code.allow_unknown = self.allow_unknown;
+
+        let mut asm_lines = vec![];
+
+        let defines_entry = modules
+            .iter()
+            .flat_map(Module::functions)
+            .any(|name| name == self.entry);
+        let bootstrap = self.bootstrap.unwrap_or(defines_entry);
+
+        if bootstrap && !defines_entry {
+            let msg = format!("entry function {} is not defined", self.entry);
+            return Err(Error::Usage(msg));
+        }
+
+        if bootstrap {
+            code.set_filename("SysInitBootstrap");
+            asm_lines.push(code.gen_init_asm(&self.entry, self.stack_base)?);
+        }
+
+        // labels defined by label and function commands, a second definition
+        // is reported at its command
+        let mut defined = HashSet::new();
+
+        for module in &modules {
+            code.set_filename(&module.name);
+
+            for line in &module.lines {
+                match code.gen_asm(line) {
+                    Ok(asm) => {
+                        if let Some(diagnostic) = redefinition(line, &asm, &mut defined) {
+                            diagnostics.push(diagnostic);
+                        }
+                        asm_lines.push(asm);
+                    }
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
+            }
+        }
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics.into());
+        }
+
+        // labels are global to the program, generated labels must not clash
+        check_labels(&asm_lines)?;
+
+        Ok(Translation {
+            asm: asm_lines,
+            warnings: code.warnings,
+        })
+    }
+
+    // add the library files defining functions that are called but not
+    // defined, until every call is resolved or no library file defines it,
+    // calls still unresolved are reported
+    fn link(&self, modules: &mut Vec<Module>, diagnostics: &mut Vec<Diagnostic>) -> Result<()> {
+        let mut library = vec![];
+
+        for dir in &self.lib_paths {
+            library_files(dir, &mut library)?;
+        }
+
+        loop {
+            let defined: HashSet<&str> = modules.iter().flat_map(Module::functions).collect();
+
+            let mut called: Vec<&str> = modules.iter().flat_map(Module::calls).collect();
+            // a forced bootstrap calls the entry function, which may come from
+            // the library as well
+            if self.bootstrap == Some(true) {
+                called.insert(0, &self.entry);
+            }
+
+            // the first library file, in search order, defining a missing function
+            let next = called
+                .iter()
+                .filter(|name| !defined.contains(*name))
+                .find_map(|name| {
+                    library.iter().position(|file: &LibraryFile| {
+                        !file.linked && file.functions.iter().any(|func| func == name)
+                    })
+                });
+
+            let Some(idx) = next else {
+                unresolved_calls(modules, &defined, diagnostics);
+                return Ok(());
+            };
+
+            let file = &mut library[idx];
+            file.linked = true;
+            collect_modules(&Input::Path(file.path.clone()), modules, diagnostics)?;
+        }
+    }
+
+    // move the modules named by order to the front, the sort is stable so
+    // every other module keeps its place
+    fn sort_modules(&self, modules: &mut [Module]) -> Result<()> {
+        if let Some(missing) = self
+            .order
+            .iter()
+            .find(|name| !modules.iter().any(|module| module.name == **name))
+        {
+            let msg = format!("module {missing} of the file order is not part of the program");
+            return Err(Error::Usage(msg));
+        }
+
+        modules.sort_by_key(|module| {
+            self.order
+                .iter()
+                .position(|name| *name == module.name)
+                .unwrap_or(self.order.len())
+        });
+        Ok(())
+    }
+
+    // translate and render the program as .asm text
+    pub fn render(&self) -> Result<String> {
+        Ok(self.translate()?.render())
+    }
+}
+
+impl Default for Translator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the label defined by a label or function command, when it was already
+// defined by an earlier one
+fn redefinition(line: &SourceLine, asm: &Asm, defined: &mut HashSet<String>) -> Option<Diagnostic> {
+    let expected = match line.command {
+        VmCommand::Label(_) => "a label defined once in its function",
+        VmCommand::Function { .. } => "a function defined once in the program",
+        _ => return None,
+    };
+
+    let label = asm
+        .instructions
+        .iter()
+        .find_map(|instruction| match instruction {
+            Instruction::Label(label) => Some(label),
+            _ => None,
+        })?;
+
+    if defined.insert(label.clone()) {
+        None
+    } else {
+        Some(line.diagnostic(1, expected))
+    }
+}
+
+// a call to a function no module defines would jump to the address of a
+// variable of the same name
+fn unresolved_calls(
+    modules: &[Module],
+    defined: &HashSet<&str>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for line in modules.iter().flat_map(|module| &module.lines) {
+        if let VmCommand::Call { name, .. } = &line.command {
+            if !defined.contains(name.as_str()) {
+                diagnostics.push(line.diagnostic(1, "a function defined by the program"));
+            }
+        }
+    }
+}
+
+// parse the modules of an input, diagnostics are collected so every file is
+// reported, not only the first one
+fn collect_modules(
+    input: &Input,
+    modules: &mut Vec<Module>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<()> {
+    let mut parsed = vec![];
+
+    match input {
+        Input::Source { module, source } => {
+            parsed.push((module.to_string(), Parser::parse_source(module, source)));
+        }
+        Input::Path(path) => {
+            for filename in vm_files(path)? {
+                let name = module_name(&filename)?;
+                parsed.push((name, Parser::new(&filename).read_lines()));
+            }
+        }
+    }
+
+    for (name, lines) in parsed {
+        match lines {
+            Ok(lines) => modules.push(Module { name, lines }),
+            Err(Error::Diagnostics(mut file_diagnostics)) => {
+                diagnostics.append(&mut file_diagnostics)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// the .vm files of dir and its subdirectories sorted by path, only the
+// function commands are read until a file is linked
+fn library_files(dir: &Path, library: &mut Vec<LibraryFile>) -> Result<()> {
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(dir).map_err(|err| with_path(dir, err))? {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            library_files(&path, library)?;
+            continue;
+        }
+
+        if path.extension().is_none_or(|ext| ext != "vm") {
+            continue;
+        }
+
+        let source = fs::read_to_string(&path).map_err(|err| with_path(&path, err))?;
+        let functions = source
+            .lines()
+            .map(|line| tokenize(0, line))
+            .filter(|tokens| tokens.len() > 1 && tokens[0].text == "function")
+            .map(|tokens| tokens[1].text.to_string())
+            .collect();
+
+        library.push(LibraryFile {
+            path,
+            functions,
+            linked: false,
+        });
+    }
+    Ok(())
+}
+
+// the file itself, or the .vm files of a directory with Sys.vm first and the
+// others sorted by name, so the output does not depend on the filesystem
+fn vm_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !fs::metadata(path)
+        .map_err(|err| with_path(path, err))?
+        .is_dir()
+    {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(path).map_err(|err| with_path(path, err))? {
+        let path = entry?.path();
+
+        // only handle vm extensions
+        if path.extension().is_some_and(|ext| ext == "vm") {
+            paths.push(path);
+        }
+    }
+
+    // write Sys init to asm first
+    paths.sort_by_key(|path| {
+        (
+            path.file_name().is_none_or(|name| name != "Sys.vm"),
+            path.clone(),
+        )
+    });
+
+    Ok(paths)
+}
+
+// static variables are named after the file, eg. Foo for dir/Foo.vm, the
+// name ends up in the asm so it must be UTF-8
+fn module_name(filename: &Path) -> Result<String> {
+    let base_fn = filename
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            let msg = "expected a file name of valid UTF-8 to name the module";
+            with_path(filename, io::Error::new(io::ErrorKind::InvalidInput, msg))
+        })?;
+
+    Ok(base_fn.split('.').next().unwrap().to_string())
+}
+
+// io errors do not say which file they are about
+pub(crate) fn with_path(path: &Path, err: io::Error) -> Error {
+    io::Error::new(err.kind(), format!("{}: {err}", path.display())).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_dir;
+
+    #[test]
+    fn test_translate_sources() {
+        let translation = Translator::new()
+            .source("Main", "push constant 7\npop static 0\n")
+            .translate()
+            .unwrap();
+
+        assert_eq!(translation.asm.len(), 2);
+        assert!(translation.render().contains("@Main.0\n"));
+        assert!(!translation.render().contains("Sys.init"));
+
+        // defining the entry function turns the bootstrap on unless it is
+        // disabled, whatever the module is named
+        let sys = "function Sys.init 0\nlabel END\ngoto END\n";
+        let rendered = Translator::new().source("Kernel", sys).render().unwrap();
+        assert!(rendered.starts_with("// Sys.init bootstrap\n@256\n"));
+
+        let rendered = Translator::new()
+            .source("Sys", "push constant 1\n")
+            .render();
+        assert!(!rendered.unwrap().contains("bootstrap"));
+
+        let rendered = Translator::new()
+            .source("Sys", sys)
+            .bootstrap(false)
+            .render()
+            .unwrap();
+        assert!(rendered.starts_with("//function Sys.init 0\n"));
+    }
+
+    #[test]
+    fn test_bootstrap_entry() {
+        let main = "function Main.main 0\nlabel END\ngoto END\n";
+
+        let rendered = Translator::new()
+            .source("Main", main)
+            .entry("Main.main")
+            .stack_base(300)
+            .render()
+            .unwrap();
+        assert!(rendered.starts_with("// Main.main bootstrap\n@300\n"));
+        assert!(rendered.contains("@Main.main\n0;JMP\n"));
+
+        // a forced bootstrap needs the entry function
+        let err = Translator::new()
+            .source("Main", main)
+            .bootstrap(true)
+            .render();
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("Sys.init is not defined"));
+    }
+
+    fn comments(translation: &Translation) -> Vec<&str> {
+        translation
+            .asm
+            .iter()
+            .map(|asm| asm.comment.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_directory_order() {
+        let dir = test_dir("order");
+        for (file, source) in [
+            ("Main.vm", "function Main.main 0\n"),
+            ("Sys.vm", "function Sys.init 0\n"),
+            ("MySys.vm", "function MySys.init 0\n"),
+            ("Array.vm", "function Array.new 0\n"),
+        ] {
+            fs::write(dir.join(file), source).unwrap();
+        }
+
+        let translation = Translator::new().input(&dir).translate().unwrap();
+        assert_eq!(
+            comments(&translation),
+            vec![
+                "// Sys.init bootstrap",
+                "//function Sys.init 0",
+                "//function Array.new 0",
+                "//function Main.main 0",
+                "//function MySys.init 0",
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_module_order() {
+        let translator = Translator::new()
+            .source("Sys", "function Sys.init 0\n")
+            .source("Array", "function Array.new 0\n")
+            .source("Main", "function Main.main 0\n")
+            .source("MySys", "function MySys.init 0\n");
+
+        let translation = translator.order(&["Main", "MySys"]).translate().unwrap();
+        assert_eq!(
+            comments(&translation)[1..],
+            [
+                "//function Main.main 0",
+                "//function MySys.init 0",
+                "//function Sys.init 0",
+                "//function Array.new 0",
+            ]
+        );
+
+        let err = Translator::new()
+            .source("Main", "function Main.main 0\n")
+            .order(&["Math"])
+            .translate();
+        let Err(Error::Usage(msg)) = err else {
+            panic!("expected a usage error, got {err:?}");
+        };
+        assert!(msg.starts_with("module Math "), "{msg}");
+    }
+
+    #[test]
+    fn test_link_library() {
+        let lib = test_dir("lib");
+        fs::create_dir_all(lib.join("memory")).unwrap();
+        for (file, source) in [
+            (
+                "Math.vm",
+                "function Math.multiply 0\ncall Memory.alloc 1\nreturn\n",
+            ),
+            ("memory/Memory.vm", "function Memory.alloc 0\nreturn\n"),
+            // never parsed, nothing calls it
+            ("Screen.vm", "function Screen.draw 0\npush nowhere x\n"),
+            ("Sys.vm", "function Sys.init 0\ncall Main.main 0\nreturn\n"),
+        ] {
+            fs::write(lib.join(file), source).unwrap();
+        }
+
+        let main = "function Main.main 0\ncall Math.multiply 2\nreturn\n";
+        let translation = Translator::new()
+            .source("Main", main)
+            .lib_path(&lib)
+            .bootstrap(false)
+            .translate()
+            .unwrap();
+        let linked = comments(&translation);
+        assert_eq!(linked.len(), 8);
+        assert_eq!(linked[3], "//function Math.multiply 0");
+        assert_eq!(linked[6], "//function Memory.alloc 0");
+
+        // a forced bootstrap links Sys.init, Sys.vm is only found on the library path
+        let translation = Translator::new()
+            .source("Main", main)
+            .lib_path(&lib)
+            .bootstrap(true)
+            .translate()
+            .unwrap();
+        let linked = comments(&translation);
+        assert_eq!(linked[0], "// Sys.init bootstrap");
+        assert!(linked.contains(&"//function Sys.init 0"));
+
+        // otherwise only calls link, a program that never calls Sys.init
+        // does not pull in Sys.vm nor turn the bootstrap on
+        let translation = Translator::new()
+            .source("Main", main)
+            .lib_path(&lib)
+            .translate()
+            .unwrap();
+        let linked = comments(&translation);
+        assert_eq!(linked[0], "//function Main.main 0");
+        assert!(!linked.contains(&"//function Sys.init 0"));
+
+        // functions defined by the program are not linked again
+        let memory = "function Memory.alloc 0\nreturn\n";
+        let translation = Translator::new()
+            .source("Main", main)
+            .source("Memory", memory)
+            .lib_path(&lib)
+            .bootstrap(false)
+            .translate()
+            .unwrap();
+        assert_eq!(
+            comments(&translation)[3..6],
+            [
+                "//function Memory.alloc 0",
+                "//return",
+                "//function Math.multiply 0"
+            ]
+        );
+        fs::remove_dir_all(&lib).unwrap();
+    }
+
+    #[test]
+    fn test_read_errors_name_the_file() {
+        let dir = test_dir("read_errors");
+        fs::write(dir.join("Main.vm"), b"push constant 1\n// \xff\n").unwrap();
+
+        let err = Translator::new().input(&dir).translate().unwrap_err();
+        assert!(matches!(err, Error::Io(_)), "{err}");
+        assert!(err.to_string().contains("Main.vm"), "{err}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // only unix file names can hold bytes that are not UTF-8
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = test_dir("non_utf8");
+        let dir = root.join(OsStr::from_bytes(b"\xff")).join("Prog");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Main.vm"), "push constant 1\n").unwrap();
+
+        // any directory can hold the program
+        assert!(Translator::new().input(&dir).translate().is_ok());
+
+        // module names end up in the asm
+        fs::write(dir.join(OsStr::from_bytes(b"Ma\xffin.vm")), "add\n").unwrap();
+        let err = Translator::new().input(&dir).translate().err().unwrap();
+        assert!(err.to_string().contains("valid UTF-8"), "{err}");
+
+        let err = Translator::new().input(dir.join("Missing.vm")).translate();
+        assert!(err.err().unwrap().to_string().contains("Missing.vm"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_unresolved_calls() {
+        let main = "function Main.main 0\ncall Nowhere.f 0\ncall Main.main 0\n";
+        let err = Translator::new().source("Main", main).translate();
+
+        let Err(Error::Diagnostics(diagnostics)) = err else {
+            panic!("expected diagnostics, got {err:?}");
+        };
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].column), (2, 6));
+        assert_eq!(diagnostics[0].token, "Nowhere.f");
+    }
+
+    #[test]
+    fn test_duplicate_labels() {
+        let main = "function Main.f 0\nlabel L\nlabel L\nfunction Main.g 0\nlabel L\n";
+        let err = Translator::new()
+            .source("Main", main)
+            .source("Other", "function Main.f 0\n")
+            .translate();
+
+        let Err(Error::Diagnostics(diagnostics)) = err else {
+            panic!("expected diagnostics, got {err:?}");
+        };
+        let found: Vec<(&str, usize, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.file.as_str(), d.line, d.token.as_str()))
+            .collect();
+        // the same label in another function is a different label
+        assert_eq!(found, vec![("Main", 3, "L"), ("Other", 1, "Main.f")]);
+    }
+
+    #[test]
+    fn test_translate_collects_diagnostics() {
+        let err = Translator::new()
+            .source("Main", "push constant x\n")
+            .source("Foo", "pop local\nadd\n")
+            .translate()
+            .err()
+            .unwrap();
+
+        let Error::Diagnostics(diagnostics) = err else {
+            panic!("expected diagnostics, got {err:?}");
+        };
+        let files: Vec<&str> = diagnostics.iter().map(|d| d.file.as_str()).collect();
+        assert_eq!(files, vec!["Main", "Foo"]);
+    }
+}
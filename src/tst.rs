@@ -0,0 +1,756 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::asm::{Asm, AsmReader};
+use crate::assembler::parse_lines;
+use crate::emulator::{Emulator, RAM_SIZE};
+use crate::error::{self, Diagnostic};
+use crate::parser::LineParser;
+use crate::translator::{with_path, Translator};
+
+// a word or punctuation of a .tst script with its 1 based position
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+// a register or memory cell read by output-list and written by set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Ram(usize),
+    A,
+    D,
+    PC,
+}
+
+// an output-list entry, eg. RAM[256]%D2.6.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Column {
+    name: String,
+    target: Target,
+    format: char,
+    pad_left: usize,
+    len: usize,
+    pad_right: usize,
+}
+
+#[derive(Debug)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Target, i16),
+    TickTock,
+    Output,
+    Echo(String),
+    Repeat(usize, Vec<Command>),
+}
+
+// outcome of running a script, lines is the produced .out table
+#[derive(Debug)]
+pub struct TstReport {
+    pub lines: Vec<String>,
+    // first line of the .out table that differs from the .cmp table
+    pub failure: Option<String>,
+    // warnings found while translating the loaded programs
+    pub warnings: Vec<Diagnostic>,
+    // messages of the echo commands in the order they ran
+    pub echo: Vec<String>,
+}
+
+impl TstReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+// interpret a CPU emulator script against the translated program
+pub fn run_script(script: impl AsRef<Path>, templates: &AsmReader) -> error::Result<TstReport> {
+    let script = script.as_ref();
+    let source = fs::read_to_string(script).map_err(|err| with_path(script, err))?;
+    let commands = parse_script(&script.display().to_string(), &source)?;
+
+    let dir = script.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let mut runner = Runner {
+        dir,
+        templates,
+        emulator: Emulator::new(vec![]),
+        output_file: None,
+        compare: vec![],
+        columns: vec![],
+        report: TstReport {
+            lines: vec![],
+            failure: None,
+            warnings: vec![],
+            echo: vec![],
+        },
+    };
+
+    runner.run(&commands)?;
+
+    if let Some(out) = &runner.output_file {
+        let mut table = runner.report.lines.join("\n");
+        table.push('\n');
+        fs::write(out, table).map_err(|err| with_path(out, err))?;
+    }
+
+    Ok(runner.report)
+}
+
+struct Runner<'a> {
+    // directory of the script, file names are resolved from here
+    dir: PathBuf,
+    templates: &'a AsmReader,
+    emulator: Emulator,
+    output_file: Option<PathBuf>,
+    compare: Vec<String>,
+    columns: Vec<Column>,
+    report: TstReport,
+}
+
+impl Runner<'_> {
+    fn run(&mut self, commands: &[Command]) -> error::Result<()> {
+        for command in commands {
+            // stop at the first comparison failure like the course tools
+            if !self.report.passed() {
+                break;
+            }
+
+            match command {
+                Command::Load(name) => self.emulator = Emulator::load(&self.load(name)?)?,
+                Command::OutputFile(name) => self.output_file = Some(self.dir.join(name)),
+                Command::CompareTo(name) => {
+                    let path = self.dir.join(name);
+                    let cmp = fs::read_to_string(&path).map_err(|err| with_path(&path, err))?;
+                    self.compare = cmp
+                        .lines()
+                        .map(|line| line.trim_end().to_string())
+                        .collect();
+                }
+                Command::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let header = format_header(&self.columns);
+                    self.output(header);
+                }
+                Command::Set(target, value) => match target {
+                    Target::Ram(addr) => self.emulator.ram[*addr] = *value,
+                    Target::A => self.emulator.a = *value,
+                    Target::D => self.emulator.d = *value,
+                    Target::PC => self.emulator.pc = *value as u16 as usize,
+                },
+                Command::TickTock => {
+                    self.emulator.step()?;
+                }
+                Command::Output => {
+                    let row = format_row(&self.columns, &self.emulator);
+                    self.output(row);
+                }
+                Command::Echo(text) => self.report.echo.push(text.clone()),
+                Command::Repeat(times, body) => {
+                    for _ in 0..*times {
+                        self.run(body)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `load Foo.asm` translates Foo.vm or the Foo directory next to the script,
+    // falls back to assembling Foo.asm as written
+    fn load(&mut self, name: &str) -> error::Result<Vec<Asm>> {
+        let stem = name.split('.').next().unwrap();
+
+        let vm_file = self.dir.join(format!("{stem}.vm"));
+        if vm_file.is_file() {
+            return self.translate(&vm_file);
+        }
+
+        // project 8 scripts load the directory they live in
+        if self.dir.file_name().is_some_and(|dir| dir == stem) {
+            return self.translate(&self.dir.clone());
+        }
+
+        let sub_dir = self.dir.join(stem);
+        if sub_dir.is_dir() {
+            return self.translate(&sub_dir);
+        }
+
+        let path = self.dir.join(name);
+        let source = fs::read_to_string(&path).map_err(|err| with_path(&path, err))?;
+
+        let lines = LineParser::parse_source(&source);
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+
+        Ok(vec![Asm {
+            comment: format!("// {name}"),
+            instructions: parse_lines(&lines)?,
+        }])
+    }
+
+    fn translate(&mut self, path: &Path) -> error::Result<Vec<Asm>> {
+        let translation = Translator::new()
+            .templates(self.templates.clone())
+            .input(path)
+            .translate()?;
+
+        self.report.warnings.extend(translation.warnings);
+        Ok(translation.asm)
+    }
+
+    fn output(&mut self, line: String) {
+        let line_num = self.report.lines.len();
+
+        if let Some(expected) = self.compare.get(line_num) {
+            if !matches_cmp(&line, expected) {
+                self.report.failure = Some(format!(
+                    "comparison failure at line {}, expected `{expected}`, found `{line}`",
+                    line_num + 1
+                ));
+            }
+        }
+
+        self.report.lines.push(line);
+    }
+}
+
+// `*` in the .cmp table matches any character
+fn matches_cmp(line: &str, expected: &str) -> bool {
+    line.len() == expected.len()
+        && line
+            .chars()
+            .zip(expected.chars())
+            .all(|(found, expected)| expected == '*' || found == expected)
+}
+
+fn format_header(columns: &[Column]) -> String {
+    let mut line = String::from("|");
+
+    for column in columns {
+        let width = column.pad_left + column.len + column.pad_right;
+        // names longer than the column are cut
+        let name: String = column.name.chars().take(width).collect();
+        let left = (width - name.len()) / 2;
+
+        line.push_str(&format!(
+            "{}{name:<w$}|",
+            " ".repeat(left),
+            w = width - left
+        ));
+    }
+
+    line
+}
+
+fn format_row(columns: &[Column], emulator: &Emulator) -> String {
+    let mut line = String::from("|");
+
+    for column in columns {
+        let value = match column.target {
+            Target::Ram(addr) => emulator.ram[addr],
+            Target::A => emulator.a,
+            Target::D => emulator.d,
+            Target::PC => emulator.pc as i16,
+        };
+
+        let formatted = match column.format {
+            'B' => format!("{:016b}", value as u16),
+            'X' => format!("{:04X}", value as u16),
+            _ => value.to_string(),
+        };
+        // binary and hex keep their low digits when the column is narrower
+        let skip = formatted.len().saturating_sub(column.len);
+        let formatted = if column.format == 'D' {
+            formatted
+        } else {
+            formatted[skip..].to_string()
+        };
+
+        line.push_str(&format!(
+            "{}{formatted:>len$}{}|",
+            " ".repeat(column.pad_left),
+            " ".repeat(column.pad_right),
+            len = column.len
+        ));
+    }
+
+    line
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = source.chars().collect();
+
+    let (mut idx, mut line, mut column) = (0, 1, 1);
+
+    // advance one char keeping track of the position
+    let advance = |idx: &mut usize, line: &mut usize, column: &mut usize| {
+        if chars[*idx] == '\n' {
+            *line += 1;
+            *column = 1;
+        } else {
+            *column += 1;
+        }
+        *idx += 1;
+    };
+
+    while idx < chars.len() {
+        let ch = chars[idx];
+        let next = chars.get(idx + 1).copied();
+
+        if ch.is_whitespace() {
+            advance(&mut idx, &mut line, &mut column);
+        } else if ch == '/' && next == Some('/') {
+            while idx < chars.len() && chars[idx] != '\n' {
+                advance(&mut idx, &mut line, &mut column);
+            }
+        } else if ch == '/' && next == Some('*') {
+            while idx < chars.len() && !(chars[idx] == '*' && chars.get(idx + 1) == Some(&'/')) {
+                advance(&mut idx, &mut line, &mut column);
+            }
+            for _ in 0..2 {
+                if idx < chars.len() {
+                    advance(&mut idx, &mut line, &mut column);
+                }
+            }
+        } else {
+            let (start_line, start_column) = (line, column);
+            let mut text = String::new();
+
+            if ch == '"' {
+                // string literal, kept with its quotes
+                text.push(ch);
+                advance(&mut idx, &mut line, &mut column);
+                while idx < chars.len() && chars[idx] != '"' && chars[idx] != '\n' {
+                    text.push(chars[idx]);
+                    advance(&mut idx, &mut line, &mut column);
+                }
+                if idx < chars.len() && chars[idx] == '"' {
+                    text.push('"');
+                    advance(&mut idx, &mut line, &mut column);
+                }
+            } else if ",;!{}".contains(ch) {
+                text.push(ch);
+                advance(&mut idx, &mut line, &mut column);
+            } else {
+                while idx < chars.len()
+                    && !chars[idx].is_whitespace()
+                    && !",;!{}\"".contains(chars[idx])
+                {
+                    text.push(chars[idx]);
+                    advance(&mut idx, &mut line, &mut column);
+                }
+            }
+
+            tokens.push(Token {
+                text,
+                line: start_line,
+                column: start_column,
+            });
+        }
+    }
+
+    tokens
+}
+
+struct ScriptParser<'a> {
+    file: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+fn parse_script(file: &str, source: &str) -> Result<Vec<Command>, Vec<Diagnostic>> {
+    let mut parser = ScriptParser {
+        file,
+        tokens: tokenize(source),
+        pos: 0,
+    };
+
+    parser
+        .commands(false)
+        .map_err(|diagnostic| vec![diagnostic])
+}
+
+impl ScriptParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token, Diagnostic> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(self.diagnostic(None, expected)),
+        }
+    }
+
+    // points at the token, or past the end of the script
+    fn diagnostic(&self, token: Option<&Token>, expected: &str) -> Diagnostic {
+        match token {
+            Some(token) => {
+                Diagnostic::new(self.file, token.line, token.column, &token.text, expected)
+            }
+            None => {
+                let (line, column) = self
+                    .tokens
+                    .last()
+                    .map_or((1, 1), |last| (last.line, last.column + last.text.len()));
+                Diagnostic::new(self.file, line, column, "", expected)
+            }
+        }
+    }
+
+    // commands until the end of the script, or the closing } of a repeat
+    fn commands(&mut self, in_block: bool) -> Result<Vec<Command>, Diagnostic> {
+        let mut commands = vec![];
+
+        loop {
+            match self.peek() {
+                None if in_block => return Err(self.diagnostic(None, "`}`")),
+                None => return Ok(commands),
+                Some(token) if in_block && token.text == "}" => {
+                    self.pos += 1;
+                    return Ok(commands);
+                }
+                Some(_) => {}
+            }
+
+            let token = self.next("a script command")?;
+
+            let command = match token.text.as_str() {
+                "repeat" => {
+                    let count = self.next("a repeat count")?;
+                    let times = count
+                        .text
+                        .parse()
+                        .map_err(|_| self.diagnostic(Some(&count), "a repeat count"))?;
+                    let open = self.next("`{`")?;
+                    if open.text != "{" {
+                        return Err(self.diagnostic(Some(&open), "`{`"));
+                    }
+                    commands.push(Command::Repeat(times, self.commands(true)?));
+                    continue;
+                }
+                "load" => {
+                    let name = self.next("a file name")?;
+                    Command::Load(name.text)
+                }
+                "output-file" => Command::OutputFile(self.next("a file name")?.text),
+                "compare-to" => Command::CompareTo(self.next("a file name")?.text),
+                "output-list" => {
+                    let mut columns = vec![];
+                    while let Some(token) = self.peek() {
+                        if is_terminator(&token.text) {
+                            break;
+                        }
+                        let token = self.next("an output column")?;
+                        columns.push(self.column(&token)?);
+                    }
+                    Command::OutputList(columns)
+                }
+                "set" => {
+                    let target = self.next("a register or RAM[address]")?;
+                    let value = self.next("a value")?;
+                    Command::Set(self.target(&target)?, self.value(&value)?)
+                }
+                "ticktock" => Command::TickTock,
+                "output" => Command::Output,
+                "echo" => {
+                    let text = self.next("a quoted string")?;
+                    Command::Echo(text.text.trim_matches('"').to_string())
+                }
+                "vmstep" => {
+                    return Err(self.diagnostic(
+                        Some(&token),
+                        "a CPU emulator command, VM emulator scripts are not supported",
+                    ))
+                }
+                _ => return Err(self.diagnostic(Some(&token), "a script command")),
+            };
+
+            let end = self.next("`,`, `;` or `!`")?;
+            if !is_terminator(&end.text) {
+                return Err(self.diagnostic(Some(&end), "`,`, `;` or `!`"));
+            }
+
+            commands.push(command);
+        }
+    }
+
+    // RAM[addr]%D2.6.2, the format defaults to %D1.6.1
+    fn column(&self, token: &Token) -> Result<Column, Diagnostic> {
+        let expected = "an output column such as RAM[0]%D2.6.2";
+        let (name, format) = token
+            .text
+            .split_once('%')
+            .unwrap_or((&token.text, "D1.6.1"));
+
+        let target = self.target(&Token {
+            text: name.to_string(),
+            ..token.clone()
+        })?;
+
+        let mut chars = format.chars();
+        let format_char = chars
+            .next()
+            .filter(|ch| "DBXS".contains(*ch))
+            .ok_or_else(|| self.diagnostic(Some(token), expected))?;
+
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|size| size.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| self.diagnostic(Some(token), expected))?;
+
+        match sizes[..] {
+            [pad_left, len, pad_right] => Ok(Column {
+                name: name.to_string(),
+                target,
+                format: format_char,
+                pad_left,
+                len,
+                pad_right,
+            }),
+            _ => Err(self.diagnostic(Some(token), expected)),
+        }
+    }
+
+    fn target(&self, token: &Token) -> Result<Target, Diagnostic> {
+        match token.text.as_str() {
+            "A" => Ok(Target::A),
+            "D" => Ok(Target::D),
+            "PC" => Ok(Target::PC),
+            text => text
+                .strip_prefix("RAM[")
+                .and_then(|addr| addr.strip_suffix(']'))
+                .and_then(|addr| addr.parse().ok())
+                .filter(|addr| *addr < RAM_SIZE)
+                .map(Target::Ram)
+                .ok_or_else(|| self.diagnostic(Some(token), "a register or RAM[address]")),
+        }
+    }
+
+    // decimal value, optionally written as %D123
+    fn value(&self, token: &Token) -> Result<i16, Diagnostic> {
+        let text = token.text.strip_prefix("%D").unwrap_or(&token.text);
+
+        text.parse()
+            .map_err(|_| self.diagnostic(Some(token), "an integer between -32768 and 32767"))
+    }
+}
+
+fn is_terminator(text: &str) -> bool {
+    matches!(text, "," | ";" | "!")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_dir;
+
+    // writes the files into a fresh directory, name is its last component
+    // so scripts can load it, the parent is removed by remove_dir
+    fn write_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = test_dir(&format!("tst_{name}")).join(name);
+        fs::create_dir_all(&dir).unwrap();
+
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir
+    }
+
+    fn remove_dir(dir: &Path) {
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    const SIMPLE_ADD_VM: &str = "push constant 7\npush constant 8\nadd\n";
+
+    const SIMPLE_ADD_TST: &str = "\
+// runs SimpleAdd.vm
+load SimpleAdd.asm,
+output-file SimpleAdd.out,
+compare-to SimpleAdd.cmp,
+output-list RAM[0]%D2.6.2 RAM[256]%D2.6.2;
+
+set RAM[0] 256;  /* stack pointer */
+
+repeat 60 {
+  ticktock;
+}
+
+output;
+";
+
+    #[test]
+    fn test_script_matches_cmp() {
+        let cmp = "|  RAM[0]  | RAM[256] |\n|     257  |      15  |\n";
+        let dir = write_dir(
+            "SimpleAdd",
+            &[
+                ("SimpleAdd.vm", SIMPLE_ADD_VM),
+                ("SimpleAdd.tst", SIMPLE_ADD_TST),
+                ("SimpleAdd.cmp", cmp),
+            ],
+        );
+
+        let script = dir.join("SimpleAdd.tst");
+        let report = run_script(&script, &AsmReader::new()).unwrap();
+
+        assert_eq!(report.failure, None);
+        assert_eq!(fs::read_to_string(dir.join("SimpleAdd.out")).unwrap(), cmp);
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_script_reports_cmp_failure() {
+        let cmp = "|  RAM[0]  | RAM[256] |\n|     257  |      16  |\n";
+        let dir = write_dir(
+            "SimpleAddFails",
+            &[
+                ("SimpleAdd.vm", SIMPLE_ADD_VM),
+                ("SimpleAdd.tst", SIMPLE_ADD_TST),
+                ("SimpleAdd.cmp", cmp),
+            ],
+        );
+
+        let script = dir.join("SimpleAdd.tst");
+        let report = run_script(&script, &AsmReader::new()).unwrap();
+
+        assert!(!report.passed());
+        assert!(report
+            .failure
+            .unwrap()
+            .starts_with("comparison failure at line 2"));
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_missing_files_name_their_path() {
+        let dir = write_dir("Missing", &[("SimpleAdd.tst", SIMPLE_ADD_TST)]);
+        let templates = AsmReader::new();
+
+        let missing = |script: &Path, file: &str| {
+            let err = run_script(script, &templates).unwrap_err();
+            assert!(matches!(err, error::Error::Io(_)), "{err}");
+            assert!(
+                err.to_string()
+                    .contains(&dir.join(file).display().to_string()),
+                "{err}"
+            );
+        };
+
+        // neither SimpleAdd.vm nor SimpleAdd.asm exist
+        missing(&dir.join("SimpleAdd.tst"), "SimpleAdd.asm");
+        missing(&dir.join("Missing.tst"), "Missing.tst");
+
+        fs::write(dir.join("SimpleAdd.vm"), SIMPLE_ADD_VM).unwrap();
+        missing(&dir.join("SimpleAdd.tst"), "SimpleAdd.cmp");
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_script_loads_own_directory() {
+        // project 8 layout, the script loads the directory it lives in
+        let dir = write_dir(
+            "CallsMain",
+            &[
+                ("Sys.vm", "function Sys.init 0\npush constant 4\ncall Main.double 1\nlabel END\ngoto END\n"),
+                ("Main.vm", "function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn\n"),
+                (
+                    "CallsMain.tst",
+                    "load CallsMain.asm, output-list RAM[0]%D1.6.1 RAM[261]%D1.6.1;\n\
+                     set RAM[0] 256, repeat 400 { ticktock; } output;",
+                ),
+                ("CallsMain.cmp", "| RAM[0] |RAM[261]|\n|    262 |      8 |\n"),
+            ],
+        );
+
+        let script = dir.join("CallsMain.tst");
+        let report = run_script(&script, &AsmReader::new()).unwrap();
+
+        assert_eq!(report.failure, None, "{:?}", report.lines);
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_report_warnings_and_echo() {
+        let dir = write_dir(
+            "Echo",
+            &[
+                ("Echo.vm", "label TOP\npush constant 1\n"),
+                (
+                    "Echo.tst",
+                    "echo \"loading\";\nload Echo.asm,\necho \"loaded\";\n",
+                ),
+            ],
+        );
+
+        let report = run_script(dir.join("Echo.tst"), &AsmReader::new()).unwrap();
+
+        // left to the caller to print
+        assert_eq!(report.echo, vec!["loading", "loaded"]);
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].token, "TOP");
+        remove_dir(&dir);
+    }
+
+    #[test]
+    fn test_format_columns() {
+        let mut emulator = Emulator::new(vec![]);
+        emulator.ram[3] = -5;
+        emulator.d = 10;
+
+        let columns = vec![
+            Column {
+                name: "RAM[3]".to_string(),
+                target: Target::Ram(3),
+                format: 'D',
+                pad_left: 1,
+                len: 6,
+                pad_right: 1,
+            },
+            Column {
+                name: "D".to_string(),
+                target: Target::D,
+                format: 'B',
+                pad_left: 0,
+                len: 4,
+                pad_right: 0,
+            },
+        ];
+
+        assert_eq!(format_header(&columns), "| RAM[3] | D  |");
+        assert_eq!(format_row(&columns, &emulator), "|     -5 |1010|");
+        assert!(matches_cmp("|     -5 |1010|", "|     ** |1010|"));
+        assert!(!matches_cmp("|     -5 |1010|", "|     -6 |1010|"));
+    }
+
+    #[test]
+    fn test_script_syntax_errors() {
+        let err = parse_script("Bad.tst", "load Bad.asm,\nset RAM[x] 3;").unwrap_err();
+        assert_eq!(
+            err[0].to_string(),
+            "Bad.tst:2:5: error: expected a register or RAM[address], found `RAM[x]`"
+        );
+
+        let err = parse_script("Bad.tst", "repeat 3 {\n  ticktock;").unwrap_err();
+        assert_eq!(err[0].expected, "`}`");
+
+        let err = parse_script("Bad.tst", "load Bad.vm,\nvmstep;").unwrap_err();
+        assert_eq!((err[0].line, err[0].column), (2, 1));
+
+        let err = parse_script("Bad.tst", "output\n").unwrap_err();
+        assert_eq!(
+            err[0].to_string(),
+            "Bad.tst:1:7: error: expected `,`, `;` or `!`, found end of line"
+        );
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+
+use crate::emulator::RAM_SIZE;
+use crate::error::Diagnostic;
+use crate::line::{Op, Segment, SourceLine, VmCommand};
+
+pub const STACK_BASE: i16 = 256;
+
+// first RAM address handed out to static variables, same as asm variables
+const STATIC_BASE: u16 = 16;
+
+// register addresses of the standard memory layout
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const TEMP: usize = 5;
+
+// a loaded source line with its names resolved
+struct Command {
+    line: SourceLine,
+    // function scoped label name for label, goto and if-goto
+    label: Option<String>,
+    // RAM address of static variables
+    static_addr: Option<u16>,
+}
+
+// stack machine interpreter for parsed .vm programs
+pub struct Vm {
+    pub ram: Vec<i16>,
+    // index of the next command to execute
+    pub pc: usize,
+    // total number of commands executed
+    pub steps: usize,
+    program: Vec<Command>,
+    labels: HashMap<String, usize>,
+    functions: HashMap<String, usize>,
+    statics: HashMap<String, u16>,
+    // stack addresses holding the return address of each active call
+    frames: Vec<usize>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP] = STACK_BASE;
+
+        Self {
+            ram,
+            pc: 0,
+            steps: 0,
+            program: vec![],
+            labels: HashMap::new(),
+            functions: HashMap::new(),
+            statics: HashMap::new(),
+            frames: vec![],
+        }
+    }
+
+    // append the lines of a file, module names the file's static variables, eg. Foo for Foo.vm
+    pub fn load(&mut self, module: &str, lines: Vec<SourceLine>) {
+        let mut current_fn: Option<String> = None;
+
+        for line in lines {
+            let idx = self.program.len();
+            let mut label = None;
+            let mut static_addr = None;
+
+            match &line.command {
+                VmCommand::Function { name, .. } => {
+                    current_fn = Some(name.to_string());
+                    self.functions.insert(name.to_string(), idx);
+                }
+                VmCommand::Label(name) | VmCommand::Goto(name) | VmCommand::IfGoto(name) => {
+                    // labels are scoped the same way as AsmGen scopes them
+                    let scoped = match &current_fn {
+                        Some(func) => format!("{func}${name}"),
+                        None => name.to_string(),
+                    };
+                    if let VmCommand::Label(_) = line.command {
+                        self.labels.insert(scoped.to_string(), idx);
+                    }
+                    label = Some(scoped);
+                }
+                VmCommand::Push(Segment::Static, index)
+                | VmCommand::Pop(Segment::Static, index) => {
+                    static_addr = Some(self.static_addr(&format!("{module}.{index}")));
+                }
+                _ => {}
+            }
+
+            self.program.push(Command {
+                line,
+                label,
+                static_addr,
+            });
+        }
+    }
+
+    // statics are allocated in order of first use, matching the assembler's variables
+    fn static_addr(&mut self, symbol: &str) -> u16 {
+        let next = STATIC_BASE + self.statics.len() as u16;
+        *self.statics.entry(symbol.to_string()).or_insert(next)
+    }
+
+    // RAM address of a static variable, eg. Foo.3
+    pub fn static_symbol(&self, symbol: &str) -> Option<u16> {
+        self.statics.get(symbol).copied()
+    }
+
+    // static variable symbols and their RAM address
+    pub fn statics(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.statics
+            .iter()
+            .map(|(symbol, addr)| (symbol.as_str(), *addr))
+    }
+
+    // stack addresses holding return addresses, outermost call first
+    pub fn return_slots(&self) -> &[usize] {
+        &self.frames
+    }
+
+    // call the entry function as the bootstrap does, returning from it halts the vm
+    pub fn bootstrap(&mut self, entry: &str) -> Result<(), String> {
+        let target = *self
+            .functions
+            .get(entry)
+            .ok_or_else(|| format!("entry function {entry} is not defined"))?;
+
+        let ret = self.program.len() as i16;
+        self.push_frame(ret, 0)
+            .ok_or_else(|| "the stack pointer is past the end of RAM".to_string())?;
+        self.pc = target;
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.program.is_empty()
+    }
+
+    // source line of the command at idx
+    pub fn line(&self, idx: usize) -> Option<&SourceLine> {
+        self.program.get(idx).map(|cmd| &cmd.line)
+    }
+
+    // halted once the pc leaves the program or sits on a goto back to itself,
+    // with only labels in between
+    pub fn is_halted(&self) -> bool {
+        let cmd = match self.program.get(self.pc) {
+            Some(cmd) => cmd,
+            None => return true,
+        };
+
+        if let (VmCommand::Goto(_), Some(label)) = (&cmd.line.command, &cmd.label) {
+            if let Some(&target) = self.labels.get(label) {
+                return target <= self.pc
+                    && self.program[target..self.pc]
+                        .iter()
+                        .all(|cmd| matches!(cmd.line.command, VmCommand::Label(_)));
+            }
+        }
+        false
+    }
+
+    // run for at most steps commands, stops early once halted,
+    // returns the number of commands executed
+    pub fn run(&mut self, steps: usize) -> Result<usize, Diagnostic> {
+        let start = self.steps;
+
+        while self.steps - start < steps && !self.is_halted() {
+            self.step()?;
+        }
+
+        Ok(self.steps - start)
+    }
+
+    // execute a single command
+    pub fn step(&mut self) -> Result<(), Diagnostic> {
+        let idx = self.pc;
+        let cmd = match self.program.get(idx) {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+
+        self.pc += 1;
+        self.steps += 1;
+
+        let line = &cmd.line;
+        // the stack pointer left RAM, eg. popping an empty stack
+        let stack_error = |vm: &Self| vm.program[idx].line.diagnostic(0, "a stack within RAM");
+
+        match &line.command {
+            VmCommand::Arith(op) => self.arith(*op).ok_or_else(|| stack_error(self))?,
+            VmCommand::Push(segment, index) => {
+                let addr = self.segment_addr(idx, *segment, *index)?;
+                let value = match addr {
+                    Some(addr) => self.ram[addr],
+                    // constant
+                    None => *index as i16,
+                };
+                self.push(value).ok_or_else(|| stack_error(self))?;
+            }
+            VmCommand::Pop(segment, index) => {
+                let addr = self
+                    .segment_addr(idx, *segment, *index)?
+                    .ok_or_else(|| line.diagnostic(1, "a segment to pop to"))?;
+                self.ram[addr] = self.pop().ok_or_else(|| stack_error(self))?;
+            }
+            VmCommand::Label(_) => {}
+            VmCommand::Goto(_) => self.pc = self.label_target(idx)?,
+            VmCommand::IfGoto(_) => {
+                let target = self.label_target(idx)?;
+                if self.pop().ok_or_else(|| stack_error(self))? != 0 {
+                    self.pc = target;
+                }
+            }
+            VmCommand::Function { n_locals, .. } => {
+                for _ in 0..*n_locals {
+                    self.push(0).ok_or_else(|| stack_error(self))?;
+                }
+            }
+            VmCommand::Call { name, n_args } => {
+                let target = *self
+                    .functions
+                    .get(name)
+                    .ok_or_else(|| line.diagnostic(1, "a defined function"))?;
+
+                self.push_frame(self.pc as i16, *n_args as i16)
+                    .ok_or_else(|| stack_error(self))?;
+                self.pc = target;
+            }
+            VmCommand::Return => {
+                // LCL points just past the 5 saved words of the caller's frame
+                let frame = ram_addr(self.ram[LCL])
+                    .filter(|frame| *frame >= 5)
+                    .ok_or_else(|| line.diagnostic(0, "a return inside a called function"))?;
+                let arg = ram_addr(self.ram[ARG])
+                    .ok_or_else(|| line.diagnostic(0, "an ARG within RAM to return to"))?;
+                let ret = self.ram[frame - 5];
+
+                let value = self.pop().ok_or_else(|| stack_error(self))?;
+                self.ram[arg] = value;
+                self.ram[SP] = (arg as i16).wrapping_add(1);
+
+                self.ram[THAT] = self.ram[frame - 1];
+                self.ram[THIS] = self.ram[frame - 2];
+                self.ram[ARG] = self.ram[frame - 3];
+                self.ram[LCL] = self.ram[frame - 4];
+
+                self.frames.pop();
+                self.pc = ret as u16 as usize;
+            }
+            VmCommand::Unknown(diagnostic) => return Err(diagnostic.clone()),
+        }
+        Ok(())
+    }
+
+    // None once SP leaves RAM
+    fn push(&mut self, value: i16) -> Option<()> {
+        let sp = ram_addr(self.ram[SP])?;
+        self.ram[sp] = value;
+        self.ram[SP] = self.ram[SP].wrapping_add(1);
+        Some(())
+    }
+
+    fn pop(&mut self) -> Option<i16> {
+        let sp = ram_addr(self.ram[SP].wrapping_sub(1))?;
+        self.ram[SP] = sp as i16;
+        Some(self.ram[sp])
+    }
+
+    // saved frame of a call, ARG points at the first of n_args arguments
+    fn push_frame(&mut self, ret: i16, n_args: i16) -> Option<()> {
+        self.frames.push(ram_addr(self.ram[SP])?);
+        self.push(ret)?;
+        for reg in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[reg])?;
+        }
+        self.ram[ARG] = self.ram[SP].wrapping_sub(5 + n_args);
+        self.ram[LCL] = self.ram[SP];
+        Some(())
+    }
+
+    fn label_target(&self, idx: usize) -> Result<usize, Diagnostic> {
+        let cmd = &self.program[idx];
+        let label = cmd.label.as_ref().unwrap();
+
+        self.labels
+            .get(label)
+            .copied()
+            .ok_or_else(|| cmd.line.diagnostic(1, "a defined label"))
+    }
+
+    // RAM address of a push or pop, None for the constant segment
+    fn segment_addr(
+        &self,
+        idx: usize,
+        segment: Segment,
+        index: u16,
+    ) -> Result<Option<usize>, Diagnostic> {
+        let cmd = &self.program[idx];
+        let line = &cmd.line;
+        let index = index as usize;
+
+        let addr = match segment {
+            Segment::Constant => return Ok(None),
+            Segment::Local => self.ram[LCL] as u16 as usize + index,
+            Segment::Argument => self.ram[ARG] as u16 as usize + index,
+            Segment::This => self.ram[THIS] as u16 as usize + index,
+            Segment::That => self.ram[THAT] as u16 as usize + index,
+            Segment::Pointer if index < 2 => THIS + index,
+            Segment::Temp if index < 8 => TEMP + index,
+            Segment::Static => cmd.static_addr.unwrap() as usize,
+            Segment::Pointer | Segment::Temp => {
+                return Err(line.diagnostic(2, "an index within the segment"));
+            }
+        };
+
+        if addr >= RAM_SIZE {
+            return Err(line.diagnostic(2, "an address within RAM"));
+        }
+
+        Ok(Some(addr))
+    }
+
+    // None once SP leaves RAM
+    fn arith(&mut self, op: Op) -> Option<()> {
+        // unary operations
+        match op {
+            Op::Neg => {
+                let y = self.pop()?;
+                return self.push(y.wrapping_neg());
+            }
+            Op::Not => {
+                let y = self.pop()?;
+                return self.push(!y);
+            }
+            _ => {}
+        }
+
+        let op = match op {
+            Op::Add => |x: i16, y: i16| x.wrapping_add(y),
+            Op::Sub => |x: i16, y: i16| x.wrapping_sub(y),
+            Op::And => |x: i16, y: i16| x & y,
+            Op::Or => |x: i16, y: i16| x | y,
+            // true is -1, false is 0
+            Op::Eq => |x: i16, y: i16| -((x == y) as i16),
+            Op::Gt => |x: i16, y: i16| -((x > y) as i16),
+            _ => |x: i16, y: i16| -((x < y) as i16),
+        };
+
+        let y = self.pop()?;
+        let x = self.pop()?;
+        self.push(op(x, y))
+    }
+}
+
+// RAM address held by a register, addresses are 16 bit unsigned as on the
+// Hack platform so negative values are past the end of RAM
+fn ram_addr(value: i16) -> Option<usize> {
+    let addr = value as u16 as usize;
+    (addr < RAM_SIZE).then_some(addr)
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn parse(source: &str) -> Vec<SourceLine> {
+        Parser::parse_source("Test.vm", source).unwrap()
+    }
+
+    fn run(source: &str) -> Vm {
+        let mut vm = Vm::new();
+        vm.load("Test", parse(source));
+        vm.run(10_000).unwrap();
+
+        assert!(vm.is_halted());
+        vm
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = "push constant 7\npush constant 8\nadd\npush constant 20\nsub\nneg\n\
+                      push constant 5\npush constant 5\neq\npush constant 3\npush constant 4\ngt\n\
+                      push constant 3\npush constant 4\nlt\npush constant 12\npush constant 10\nand\n\
+                      push constant 12\npush constant 10\nor\npush constant 0\nnot\n";
+        let vm = run(source);
+
+        assert_eq!(vm.ram[SP], 263);
+        assert_eq!(&vm.ram[256..263], &[5, -1, 0, -1, 8, 14, -1]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let source = "push constant 3030\npop pointer 0\npush constant 3040\npop pointer 1\n\
+                      push constant 32\npop this 2\npush constant 46\npop that 6\n\
+                      push constant 10\npop local 0\npush constant 21\npop argument 1\n\
+                      push constant 36\npop temp 6\npush constant 11\npop static 3\n\
+                      push this 2\npush that 6\nadd\npush temp 6\nadd\npush static 3\nadd\n\
+                      push local 0\npush argument 1\nadd\npush pointer 1\n";
+        let mut vm = Vm::new();
+        vm.ram[LCL] = 300;
+        vm.ram[ARG] = 400;
+        vm.load("Test", parse(source));
+        vm.run(1000).unwrap();
+
+        assert_eq!(vm.ram[3032], 32);
+        assert_eq!(vm.ram[3046], 46);
+        assert_eq!(vm.ram[300], 10);
+        assert_eq!(vm.ram[401], 21);
+        assert_eq!(vm.ram[11], 36);
+        assert_eq!(vm.static_symbol("Test.3"), Some(16));
+        assert_eq!(vm.ram[16], 11);
+        assert_eq!(&vm.ram[256..259], &[125, 31, 3040]);
+    }
+
+    #[test]
+    fn test_loop_halts() {
+        // sum 1..=5 into local 0
+        let source = "function Main.main 1\npush constant 5\npop temp 0\nlabel LOOP\n\
+                      push temp 0\npush local 0\nadd\npop local 0\n\
+                      push temp 0\npush constant 1\nsub\npop temp 0\n\
+                      push temp 0\nif-goto LOOP\nlabel END\ngoto END\n";
+        let mut vm = Vm::new();
+        vm.ram[LCL] = 256;
+        vm.load("Main", parse(source));
+        vm.run(1000).unwrap();
+
+        assert!(vm.is_halted());
+        assert_eq!(vm.pc, 15);
+        assert_eq!(vm.ram[256], 15);
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let source = "function Sys.init 0\npush constant 4\npush constant 5\ncall Main.mult 2\n\
+                      pop static 0\nlabel HALT\ngoto HALT\n\
+                      function Main.mult 1\npush argument 0\npush argument 1\nadd\npop local 0\n\
+                      push local 0\npush local 0\nadd\nreturn\n";
+        let mut vm = Vm::new();
+        vm.load("Sys", parse(source));
+        vm.bootstrap("Sys.init").unwrap();
+
+        // return address, LCL, ARG, THIS, THAT of the bootstrap call
+        assert_eq!(vm.ram[SP], 261);
+        assert_eq!(vm.ram[LCL], 261);
+        assert_eq!(vm.ram[ARG], 256);
+
+        vm.run(1000).unwrap();
+
+        assert!(vm.is_halted());
+        assert_eq!(vm.ram[16], 18);
+        assert_eq!(vm.ram[SP], 261);
+        assert_eq!(vm.ram[LCL], 261);
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let mut vm = Vm::new();
+        vm.load("Test", parse("push constant 1\ncall Main.none 0\n"));
+
+        let diagnostic = vm.run(10).unwrap_err();
+
+        assert_eq!(diagnostic.line, 2);
+        assert_eq!(diagnostic.token, "Main.none");
+        assert!(vm.bootstrap("Sys.init").is_err());
+    }
+
+    #[test]
+    fn test_stack_errors() {
+        let run_err = |sp: i16, source: &str| {
+            let mut vm = Vm::new();
+            vm.ram[SP] = sp;
+            vm.load("Test", Parser::parse_source("Test", source).unwrap());
+            vm.run(10).unwrap_err()
+        };
+
+        // return at top level, LCL is 0 so there is no saved frame
+        let diagnostic = run_err(256, "push constant 1\nreturn\n");
+        assert_eq!((diagnostic.line, diagnostic.token.as_str()), (2, "return"));
+
+        // popping an empty stack and pushing with a negative SP
+        assert_eq!(run_err(0, "pop local 0\n").expected, "a stack within RAM");
+        assert_eq!(run_err(-1, "push constant 1\n").line, 1);
+        assert_eq!(run_err(-3, "add\n").token, "add");
+    }
+}